@@ -2,16 +2,28 @@ use std::{
     fmt::Display,
     fs::File,
     io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::{Context, Error};
-use feed2imap::sync::Input;
+use anyhow::{anyhow, Context, Error};
+use feed2imap::{fetch, imap::Security, state, sync::Input};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Default)]
 pub struct Config {
-    pub imap: Imap,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imap: Option<Imap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maildir: Option<Maildir>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<Smtp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mbox: Option<Mbox>,
+    #[serde(default)]
+    pub state: State,
+    #[serde(default)]
+    pub fetch: Fetch,
     pub feeds: Vec<Feed>,
 }
 
@@ -19,6 +31,8 @@ pub struct Config {
 pub struct Imap {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub security: Security,
     pub username: String,
     pub password: String,
     pub name: String,
@@ -26,14 +40,261 @@ pub struct Imap {
     pub default_folder: String,
 }
 
+#[derive(Deserialize, Serialize, Default)]
+pub struct Maildir {
+    /// root of the Maildir++ tree, feed folders are stored in its sub folders
+    pub path: String,
+    pub name: String,
+    pub email: String,
+    #[serde(default = "default_maildir_folder")]
+    pub default_folder: String,
+}
+
+fn default_maildir_folder() -> String {
+    "INBOX".to_owned()
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct Smtp {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub security: Security,
+    /// leave empty to skip authentication
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    pub name: String,
+    /// address receiving the entries, also used as envelope sender
+    pub email: String,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct Mbox {
+    /// mbox file receiving all entries, or directory of the feed files when `per_feed` is set
+    pub path: String,
+    #[serde(default)]
+    pub per_feed: bool,
+    pub name: String,
+    pub email: String,
+}
+
+/// Output configured to receive feed entries.
+pub enum Output<'a> {
+    Imap(&'a Imap),
+    Maildir(&'a Maildir),
+    Smtp(&'a Smtp),
+    Mbox(&'a Mbox),
+}
+
+impl Output<'_> {
+    /// name of the recipient of the entries
+    pub fn name(&self) -> &str {
+        match self {
+            Output::Imap(imap) => &imap.name,
+            Output::Maildir(maildir) => &maildir.name,
+            Output::Smtp(smtp) => &smtp.name,
+            Output::Mbox(mbox) => &mbox.name,
+        }
+    }
+
+    /// address of the recipient of the entries
+    pub fn email(&self) -> &str {
+        match self {
+            Output::Imap(imap) => &imap.email,
+            Output::Maildir(maildir) => &maildir.email,
+            Output::Smtp(smtp) => &smtp.email,
+            Output::Mbox(mbox) => &mbox.email,
+        }
+    }
+
+    /// where entries of feeds without folder are delivered, `None` if the output has no folders
+    pub fn default_folder(&self) -> Option<&str> {
+        match self {
+            Output::Imap(imap) => Some(&imap.default_folder),
+            Output::Maildir(maildir) => Some(&maildir.default_folder),
+            Output::Smtp(_) | Output::Mbox(_) => None,
+        }
+    }
+}
+
+impl Config {
+    pub fn output(&self) -> Result<Output<'_>, Error> {
+        let mut outputs = Vec::new();
+        if let Some(ref imap) = self.imap {
+            outputs.push(Output::Imap(imap));
+        }
+        if let Some(ref maildir) = self.maildir {
+            outputs.push(Output::Maildir(maildir));
+        }
+        if let Some(ref smtp) = self.smtp {
+            outputs.push(Output::Smtp(smtp));
+        }
+        if let Some(ref mbox) = self.mbox {
+            outputs.push(Output::Mbox(mbox));
+        }
+        if outputs.len() > 1 {
+            return Err(anyhow!(
+                "only one of [imap], [maildir], [smtp] and [mbox] can be configured"
+            ));
+        }
+        outputs.pop().ok_or_else(|| {
+            anyhow!("no output configured, add an [imap], [maildir], [smtp] or [mbox] section")
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct State {
+    /// path to the state file, default to ~/.local/state/feed2imap/state.toml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// also look for already delivered entries in the mailbox, not only in the state
+    #[serde(default = "default_reconcile")]
+    pub reconcile: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            path: None,
+            reconcile: default_reconcile(),
+        }
+    }
+}
+
+fn default_reconcile() -> bool {
+    true
+}
+
+impl State {
+    pub fn path(&self) -> Result<PathBuf, Error> {
+        match self.path {
+            Some(ref path) => Ok(PathBuf::from(path)),
+            None => state::default_path().ok_or_else(|| anyhow!("no state directory found")),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Fetch {
+    /// how many feeds are fetched at once
+    pub concurrency: usize,
+    /// how many feeds of a same host are fetched at once
+    pub per_host_concurrency: usize,
+    /// minimum delay between two requests to a same host, in milliseconds
+    pub host_delay_ms: u64,
+    pub connect_timeout_secs: u64,
+    /// maximum time waiting for the next part of a response, in seconds
+    pub read_timeout_secs: u64,
+    /// maximum size of a feed, in megabytes
+    pub max_size_mb: u64,
+    /// proxy of all feeds, e.g. `http://proxy:3128` or `socks5h://localhost:9050`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// how many times a request failing temporarily is tried again
+    pub retries: u32,
+    /// delay before the first retry, doubled on each following retry, in milliseconds
+    pub retry_delay_ms: u64,
+    /// longest delay before a retry, in seconds
+    pub max_retry_delay_secs: u64,
+    /// repair the mistakes making feeds invalid, e.g. HTML entities or control characters
+    pub lenient: bool,
+    /// after a sync, rewrite the url of moved feeds and disable gone feeds
+    pub auto_fix: bool,
+    /// syncs trying to make a mail of an entry before giving up on it, tried forever if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_entry_attempts: Option<u32>,
+}
+
+impl Fetch {
+    pub fn fetcher(&self) -> Result<fetch::Fetcher, Error> {
+        fetch::Fetcher::new(&fetch::Settings {
+            connect_timeout: Duration::from_secs(self.connect_timeout_secs),
+            read_timeout: Duration::from_secs(self.read_timeout_secs),
+            max_size: self.max_size_mb * 1024 * 1024,
+            proxy: self.proxy.clone(),
+            retries: self.retries,
+            retry_delay: Duration::from_millis(self.retry_delay_ms),
+            max_retry_delay: Duration::from_secs(self.max_retry_delay_secs),
+            lenient: self.lenient,
+        })
+    }
+}
+
+impl Default for Fetch {
+    fn default() -> Self {
+        Fetch {
+            concurrency: 16,
+            per_host_concurrency: 2,
+            host_delay_ms: 0,
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            max_size_mb: 10,
+            proxy: None,
+            retries: 3,
+            retry_delay_ms: 1000,
+            max_retry_delay_secs: 60,
+            lenient: false,
+            auto_fix: false,
+            max_entry_attempts: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Default, Clone)]
 pub struct Feed {
+    /// url of the feed, `file:///path` for a local file, or `-` for the standard input. Not
+    /// set for a feed printed by `command`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub url: String,
+    /// IMAP folder receiving this feed entries, `imap.default_folder` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    /// disabled feeds are not synced, e.g. once gone
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub disabled: bool,
+    #[serde(flatten)]
+    pub options: fetch::Options,
+    /// name of a feed printed by `command`, standing for its url
+    #[serde(skip)]
+    pub command_name: String,
+}
+
+impl Feed {
+    /// Checks the feed has a url or a command, and names the feeds printed by a command.
+    fn validate(&mut self) -> Result<(), Error> {
+        match (self.url.is_empty(), &self.options.command) {
+            (true, Some(command)) => self.command_name = format!("command:{}", command.line()),
+            (true, None) => return Err(anyhow!("a feed has neither url nor command")),
+            (false, Some(_)) => return Err(anyhow!("feed {} has both url and command", self.url)),
+            (false, None) => {}
+        }
+        Ok(())
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 impl Input for Feed {
     fn url(&self) -> &str {
-        &self.url
+        if self.url.is_empty() {
+            &self.command_name
+        } else {
+            &self.url
+        }
+    }
+
+    fn folder(&self) -> Option<&str> {
+        self.folder.as_deref()
+    }
+
+    fn options(&self) -> &fetch::Options {
+        &self.options
     }
 }
 
@@ -41,12 +302,18 @@ pub fn load<P: AsRef<Path> + Display>(path: P) -> Result<Config, Error> {
     let mut file = File::open(&path).with_context(|| format!("failed opening {}", &path))?;
     let mut content = String::new();
     file.read_to_string(&mut content)?;
-    let config = toml::from_str(&content)?;
+    let mut config: Config = toml::from_str(&content)?;
+    for feed in config.feeds.iter_mut() {
+        feed.validate()?;
+    }
     Ok(config)
 }
 
 pub fn dump_default() -> Result<(), Error> {
-    let config = Config::default();
+    let config = Config {
+        imap: Some(Imap::default()),
+        ..Config::default()
+    };
     let str = toml::to_string_pretty(&config)?;
     println!("{}", str);
     Ok(())
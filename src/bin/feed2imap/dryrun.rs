@@ -0,0 +1,102 @@
+use anyhow::{Context, Error};
+use feed2imap::{mbox, sync};
+use std::path::PathBuf;
+
+use crate::config;
+
+/// Where the entries would have been delivered.
+#[derive(Clone)]
+pub enum Destination {
+    /// a folder, the given default one for feeds without folder
+    Folder(String),
+    /// a mbox file, one per feed when `per_feed` is set
+    Mbox { path: PathBuf, per_feed: bool },
+    /// no folder nor file, e.g. sent by mail
+    Nowhere,
+}
+
+impl Destination {
+    pub fn of(output: &config::Output) -> Self {
+        match (output, output.default_folder()) {
+            (config::Output::Mbox(mbox), _) => Destination::Mbox {
+                path: PathBuf::from(&mbox.path),
+                per_feed: mbox.per_feed,
+            },
+            (_, Some(folder)) => Destination::Folder(folder.to_owned()),
+            (_, None) => Destination::Nowhere,
+        }
+    }
+}
+
+/// Output reporting the entries that would be appended, and optionally saving them as `.eml`
+/// files, without ever appending to `inner`.
+#[derive(Clone)]
+pub struct DryRunOutput<TOutput> {
+    inner: TOutput,
+    destination: Destination,
+    out: Option<PathBuf>,
+}
+
+impl<TOutput> DryRunOutput<TOutput> {
+    pub fn new(inner: TOutput, destination: Destination, out: Option<PathBuf>) -> Self {
+        DryRunOutput {
+            inner,
+            destination,
+            out,
+        }
+    }
+}
+
+impl<TOutput> sync::Output for DryRunOutput<TOutput>
+where
+    TOutput: sync::Output + Sync,
+{
+    async fn contains(&self, feed: &str, id: &str, folder: Option<&str>) -> Result<bool, Error> {
+        self.inner.contains(feed, id, folder).await
+    }
+
+    async fn append(
+        &self,
+        feed: &str,
+        id: &str,
+        mail: &Vec<u8>,
+        folder: Option<&str>,
+    ) -> Result<(), Error> {
+        // `.eml` files are saved in the folder they would be appended to, if any
+        let folder = match self.destination {
+            Destination::Folder(ref default_folder) => {
+                let folder = folder.unwrap_or(default_folder);
+                println!("would append: {} from {} to {}", id, feed, folder);
+                Some(folder)
+            }
+            Destination::Mbox { ref path, per_feed } => {
+                let path = mbox::file_path(path, per_feed, feed, folder);
+                println!("would append: {} from {} to {}", id, feed, path.display());
+                None
+            }
+            Destination::Nowhere => {
+                println!("would send: {} from {}", id, feed);
+                None
+            }
+        };
+        if let Some(ref out) = self.out {
+            let dir = folder
+                .unwrap_or_default()
+                .split('/')
+                .filter(|segment| !segment.is_empty() && *segment != "..")
+                .fold(out.clone(), |dir, segment| dir.join(segment));
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .with_context(|| format!("could not create {}", dir.display()))?;
+            let path = dir.join(format!("{}.eml", id));
+            tokio::fs::write(&path, mail)
+                .await
+                .with_context(|| format!("could not write {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn sender(&self) -> Option<&str> {
+        self.inner.sender()
+    }
+}
@@ -1,12 +1,16 @@
 use anyhow::{anyhow, Error};
 use clap::{Args, Parser, Subcommand};
 use directories::BaseDirs;
-use feed2imap::{fetch, imap, sync, transform};
-use std::sync::Arc;
+use feed2imap::scheduler::{self, Scheduler};
+use feed2imap::sync::Input;
+use feed2imap::{discover, fetch, imap, maildir, mbox, smtp, state, sync, transform};
+use std::{io::Write, path::PathBuf, sync::Arc, time::Duration};
 
+use crate::dryrun::{Destination, DryRunOutput};
 use crate::reporter::{CliReporter, SimpleReporter};
 
 pub mod config;
+pub mod dryrun;
 pub mod reporter;
 
 #[derive(Parser)]
@@ -47,19 +51,42 @@ enum Command {
     #[command()]
     Config,
 
+    /// rewrite the url of moved feeds and disable gone feeds
+    #[command()]
+    Fix,
+
     /// list feeds
     #[command()]
     List,
 
     /// fetch feeds and send new entries by mail
     #[command()]
-    Sync,
+    Sync(SyncArgs),
 }
 
 #[derive(Args)]
 struct AddArgs {
-    /// url of the feed
+    /// url of the feed, or of a page linking to it
     url: String,
+
+    /// add the first feed found, instead of asking which one
+    #[arg(long, default_value_t = false)]
+    first: bool,
+
+    /// IMAP folder receiving the feed entries, default to imap.default_folder
+    #[arg(long)]
+    folder: Option<String>,
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    /// report the entries that would be appended, without appending them
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// with --dry-run, also write each entry as a .eml file in this directory
+    #[arg(long, requires = "dry_run")]
+    out: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -70,8 +97,9 @@ async fn main() -> () {
     let result = match &cli.command {
         Command::Add(ref args) => add_feed(&cli, args).await,
         Command::Config => config(&cli).await,
+        Command::Fix => fix_feeds(&cli).await,
         Command::List => list_feeds(&cli).await,
-        Command::Sync => sync_feeds(&cli).await,
+        Command::Sync(ref args) => sync_feeds(&cli, args).await,
     };
     if let Err(err) = result {
         eprintln!("ERROR: {}", err);
@@ -86,21 +114,229 @@ async fn config(_cli: &Cli) -> Result<(), Error> {
     config::dump_default()
 }
 
-async fn sync_feeds(cli: &Cli) -> Result<(), Error> {
+async fn sync_feeds(cli: &Cli, args: &SyncArgs) -> Result<(), Error> {
     let config = Arc::new(config::load(&cli.config_path())?);
-    log::debug!("connecting to mail server");
-    let client = imap::client(&config.imap.username, &config.imap.password).await?;
-    let output = imap::new_output(client, &config.imap.default_folder).await?;
-    let syncer = sync::Syncer::new(&config.imap.name, &config.imap.email);
+    let store = if args.dry_run {
+        // the state is left untouched, nothing was delivered
+        state::load_read_only(config.state.path()?).await?
+    } else {
+        state::load(config.state.path()?).await?
+    };
+    match config.output()? {
+        config::Output::Imap(imap_config) => {
+            log::debug!("connecting to mail server");
+            let client = imap::client(
+                &imap_config.host,
+                imap_config.port,
+                imap_config.security,
+                &imap_config.username,
+                &imap_config.password,
+            )
+            .await?;
+            let output = imap::new_output(client, &imap_config.default_folder, store.clone());
+            sync_to(cli, args, &config, store, output).await
+        }
+        config::Output::Maildir(maildir_config) => {
+            let output = maildir::new_output(&maildir_config.path, &maildir_config.default_folder);
+            sync_to(cli, args, &config, store, output).await
+        }
+        config::Output::Smtp(smtp_config) => {
+            let output = smtp::new_output(
+                &smtp_config.host,
+                smtp_config.port,
+                smtp_config.security,
+                &smtp_config.username,
+                &smtp_config.password,
+                &smtp_config.email,
+            )?;
+            sync_to(cli, args, &config, store, output).await
+        }
+        config::Output::Mbox(mbox_config) => {
+            let output = mbox::new_output(&mbox_config.path, mbox_config.per_feed);
+            sync_to(cli, args, &config, store, output).await
+        }
+    }
+}
+
+async fn sync_to<TOutput>(
+    cli: &Cli,
+    args: &SyncArgs,
+    config: &config::Config,
+    store: state::Store,
+    output: TOutput,
+) -> Result<(), Error>
+where
+    TOutput: sync::Output + Sync + Send + Clone + 'static,
+{
+    let output = state::Output::new(output, store.clone(), config.state.reconcile);
+    let output_config = config.output()?;
+    let scheduler = Scheduler::new(
+        config.fetch.concurrency,
+        config.fetch.per_host_concurrency,
+        Duration::from_millis(config.fetch.host_delay_ms),
+    );
+    let syncer = sync::Syncer::new(
+        output_config.name(),
+        output_config.email(),
+        store.clone(),
+        scheduler,
+        config.fetch.fetcher()?,
+        config.fetch.max_entry_attempts,
+    );
+    let report = if args.dry_run {
+        let output = DryRunOutput::new(output, Destination::of(&output_config), args.out.clone());
+        sync_with_reporter(cli, config, syncer, output).await?
+    } else {
+        let report = sync_with_reporter(cli, config, syncer, output).await?;
+        // saved first, so that the delivered entries are kept even if fixes fail
+        store.save().await?;
+        let fixes = Fixes::from_report(&report);
+        if !fixes.is_empty() {
+            if config.fetch.auto_fix {
+                fixes.apply(cli, &store).await?;
+                store.save().await?;
+            } else {
+                println!("some feeds moved or are gone, run `feed2imap fix` to update them");
+            }
+        }
+        report
+    };
+
+    let (new, skipped, failed, given_up) =
+        report.feeds.iter().fold((0, 0, 0, 0), |counts, feed| {
+            (
+                counts.0 + feed.new,
+                counts.1 + feed.skipped,
+                counts.2 + feed.failed,
+                counts.3 + feed.given_up,
+            )
+        });
+    let unchanged = report.feeds.iter().filter(|feed| feed.unchanged).count();
+    let repaired = report.feeds.iter().filter(|feed| feed.repaired).count();
+    println!(
+        "{} feeds ({} unchanged, {} repaired): {} new, {} skipped, {} failed, {} given up entries",
+        report.feeds.len(),
+        unchanged,
+        repaired,
+        new,
+        skipped,
+        failed,
+        given_up
+    );
+    let failures: Vec<&str> = report.failures().map(|feed| feed.url.as_str()).collect();
+    if !failures.is_empty() {
+        return Err(anyhow!(
+            "{} feeds failed: {}",
+            failures.len(),
+            failures.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+async fn sync_with_reporter<TOutput>(
+    cli: &Cli,
+    config: &config::Config,
+    syncer: Arc<sync::Syncer>,
+    output: TOutput,
+) -> Result<sync::SyncReport, Error>
+where
+    TOutput: sync::Output + Sync + Send + Clone + 'static,
+{
+    let feeds: Vec<config::Feed> = config
+        .feeds
+        .iter()
+        .filter(|feed| !feed.disabled)
+        .cloned()
+        .collect();
     if cli.batch {
         let reporter = SimpleReporter {};
-        syncer.sync(&config.feeds, output, reporter).await?;
+        syncer.sync(&feeds, output, reporter).await
     } else {
         let reporter = CliReporter::new()?;
-        syncer.sync(&config.feeds, output, reporter).await?;
-    };
+        syncer.sync(&feeds, output, reporter).await
+    }
+}
 
-    Ok(())
+/// Feeds whose url must be rewritten or which must be disabled in the configuration.
+#[derive(Default)]
+struct Fixes {
+    moved: Vec<(String, String)>,
+    gone: Vec<String>,
+}
+
+impl Fixes {
+    fn from_report(report: &sync::SyncReport) -> Fixes {
+        let mut fixes = Fixes::default();
+        for feed in &report.feeds {
+            if feed.gone {
+                fixes.gone.push(feed.url.clone());
+            } else if let Some(ref moved_to) = feed.moved_to {
+                fixes.moved.push((feed.url.clone(), moved_to.clone()));
+            }
+        }
+        fixes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.moved.is_empty() && self.gone.is_empty()
+    }
+
+    /// Updates the configuration file, and moves the state of moved feeds to their new url.
+    async fn apply(&self, cli: &Cli, store: &state::Store) -> Result<(), Error> {
+        // reloaded, so that options are written back as found in the file
+        let mut config = config::load(cli.config_path())?;
+        for feed in config.feeds.iter_mut() {
+            if self.gone.contains(&feed.url) {
+                println!("disabled: {}", feed.url);
+                feed.disabled = true;
+            } else if let Some((_, moved_to)) = self.moved.iter().find(|(url, _)| *url == feed.url)
+            {
+                println!("moved: {} to {}", feed.url, moved_to);
+                store.rename_feed(&feed.url, moved_to).await;
+                feed.url = moved_to.clone();
+            }
+        }
+        config::save(&config, cli.config_path())
+    }
+}
+
+async fn fix_feeds(cli: &Cli) -> Result<(), Error> {
+    let config = config::load(cli.config_path())?;
+    let store = state::load(config.state.path()?).await?;
+    let fetcher = config.fetch.fetcher()?;
+    let mut fixes = Fixes::default();
+    // only feeds fetched over HTTP can be redirected or gone, not the feeds printed by a
+    // command, read from the standard input or from a file
+    let fetched_feeds = config.feeds.iter().filter(|feed| {
+        !feed.disabled && feed.options.command.is_none() && scheduler::host_of(&feed.url).is_some()
+    });
+    for feed in fetched_feeds {
+        log::info!("fetch {}", feed.url);
+        match fetcher
+            .url_if_modified(
+                &feed.url,
+                &feed.options,
+                &fetch::Validators::default(),
+                None,
+            )
+            .await
+        {
+            Ok(fetched) => {
+                if let Some(moved_to) = fetched.moved_to {
+                    fixes.moved.push((feed.url.clone(), moved_to));
+                }
+            }
+            Err(err) if err.is::<fetch::Gone>() => fixes.gone.push(feed.url.clone()),
+            Err(err) => eprintln!("ERROR: {}: {}", feed.url, err),
+        }
+    }
+    if fixes.is_empty() {
+        println!("no feed to fix");
+        return Ok(());
+    }
+    fixes.apply(cli, &store).await?;
+    store.save().await
 }
 
 async fn add_feed(cli: &Cli, args: &AddArgs) -> Result<(), Error> {
@@ -108,34 +344,70 @@ async fn add_feed(cli: &Cli, args: &AddArgs) -> Result<(), Error> {
 
     log::info!("fetch {}", args.url);
 
-    let feed = fetch::url(&args.url).await?;
-    let title = transform::extract_feed_title(&feed)?;
-    let email = transform::extract_email(&feed, feed.entries.first().expect("no entries in feed"))?;
-
-    println!("Title: {}\nEmail: {}\nUrl: {}", title, email, args.url);
+    let fetcher = config.fetch.fetcher()?;
+    let mut candidates = discover::candidates(&fetcher, &args.url).await?;
+    if candidates.is_empty() {
+        return Err(anyhow!("no feed found at {}", args.url));
+    }
+    let index = if args.first || candidates.len() == 1 {
+        0
+    } else {
+        pick_candidate(&candidates)?
+    };
+    let candidate = candidates.swap_remove(index);
+    let feed = &candidate.feed;
+    let title = transform::extract_feed_title(feed)?;
+    println!("Title: {}", title);
+    if let Some(entry) = feed.entries.first() {
+        println!("Email: {}", transform::extract_email(feed, entry)?);
+    }
+    println!("Url: {}", candidate.url);
 
-    if config.feeds.iter().any(|feed| feed.url == args.url) {
-        return Err(anyhow!("{} already in config", args.url));
+    if config.feeds.iter().any(|feed| feed.url == candidate.url) {
+        return Err(anyhow!("{} already in config", candidate.url));
     }
 
     config.feeds.push(config::Feed {
-        url: args.url.to_owned(),
+        url: candidate.url.to_owned(),
+        folder: args.folder.to_owned(),
+        ..config::Feed::default()
     });
     config::save(&config, &cli.config_path())?;
 
     Ok(())
 }
 
+/// Asks which of the feeds found should be added.
+fn pick_candidate(candidates: &[discover::Candidate]) -> Result<usize, Error> {
+    for (i, candidate) in candidates.iter().enumerate() {
+        let title = transform::extract_feed_title(&candidate.feed)?;
+        println!("{}. {} <{}>", i + 1, title, candidate.url);
+    }
+    loop {
+        print!("feed to add [1-{}]: ", candidates.len());
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Err(anyhow!("no feed picked"));
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(n) if (1..=candidates.len()).contains(&n) => return Ok(n - 1),
+            _ => println!("{} is not one of the feeds", answer.trim()),
+        }
+    }
+}
+
 async fn list_feeds(cli: &Cli) -> Result<(), Error> {
     let config = config::load(&cli.config_path())?;
-    for feed in config.feeds {
-        let full_feed = fetch::url(&feed.url).await?;
+    let fetcher = config.fetch.fetcher()?;
+    for feed in config.feeds.into_iter().filter(|feed| !feed.disabled) {
+        let full_feed = fetcher.url(feed.url(), &feed.options).await?;
         let title = transform::extract_feed_title(&full_feed)?;
-        let email = transform::extract_email(
-            &full_feed,
-            full_feed.entries.first().expect("no entries in feed"),
-        )?;
-        println!("Title: {}\nEmail: {}\nUrl: {}\n", title, email, feed.url);
+        println!("Title: {}", title);
+        if let Some(entry) = full_feed.entries.first() {
+            println!("Email: {}", transform::extract_email(&full_feed, entry)?);
+        }
+        println!("Url: {}\n", feed.url());
     }
     Ok(())
 }
@@ -51,11 +51,39 @@ impl sync::Reporter for CliReporter {
         }
     }
 
+    async fn on_unchanged(&self, feed: &str) {
+        let index = self.index.lock().await;
+        if let Some(pb) = index.get(feed) {
+            pb.set_message("unchanged");
+        }
+    }
+
+    async fn on_moved(&self, feed: &str, url: &str) {
+        let index = self.index.lock().await;
+        if let Some(pb) = index.get(feed) {
+            pb.set_message(format!("moved to {}", url));
+        }
+    }
+
+    async fn on_gone(&self, feed: &str) {
+        let index = self.index.lock().await;
+        if let Some(pb) = index.get(feed) {
+            pb.set_message("gone");
+        }
+    }
+
+    async fn on_repaired(&self, feed: &str) {
+        let index = self.index.lock().await;
+        if let Some(pb) = index.get(feed) {
+            pb.set_message("repaired");
+        }
+    }
+
     async fn on_end(&self, feed: &str, result: &Result<(), Error>) {
         let index = self.index.lock().await;
         if let Some(pb) = index.get(feed) {
             if let Err(err) = result {
-                pb.set_message(format!("{}", err));
+                pb.set_message(format!("{:#}", err));
             }
         }
     }
@@ -77,9 +105,25 @@ impl Reporter for SimpleReporter {
         println!("processed: {} one more !", feed);
     }
 
+    async fn on_unchanged(&self, feed: &str) {
+        println!("unchanged: {}", feed);
+    }
+
+    async fn on_moved(&self, feed: &str, url: &str) {
+        println!("moved: {} to {}", feed, url);
+    }
+
+    async fn on_gone(&self, feed: &str) {
+        println!("gone: {}", feed);
+    }
+
+    async fn on_repaired(&self, feed: &str) {
+        println!("repaired: {}", feed);
+    }
+
     async fn on_end(&self, feed: &str, result: &Result<(), Error>) {
         if let Err(err) = result {
-            println!("ERROR: {}: {}", feed, err);
+            println!("ERROR: {}: {:#}", feed, err);
         } else {
             println!("synced: {}", feed);
         }
@@ -0,0 +1,177 @@
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use encoding_rs::{Encoding, UTF_8};
+
+/// Bytes of an HTML page searched for its `<meta charset>`, as browsers do.
+const META_PRESCAN: usize = 1024;
+
+/// Transcodes `content` to UTF-8. Its encoding is told by its byte order mark, else by the
+/// charset of its `content_type`, else by its XML declaration or HTML meta, else guessed. When
+/// the charset and the XML declaration disagree, the one matching the content wins.
+pub fn to_utf8(content: Vec<u8>, content_type: Option<&str>) -> Vec<u8> {
+    let (encoding, bom_length) = match Encoding::for_bom(&content) {
+        Some(bom) => bom,
+        None => {
+            let header = content_type
+                .and_then(content_type_charset)
+                .and_then(|label| Encoding::for_label(label.as_bytes()));
+            let prolog =
+                xml_encoding(&content).and_then(|label| Encoding::for_label(label.as_bytes()));
+            let declared = match (header, prolog) {
+                (Some(header), Some(prolog)) if header != prolog => {
+                    Some(disagreement(&content, header, prolog))
+                }
+                _ => header.or(prolog).or_else(|| {
+                    meta_charset(&content).and_then(|label| Encoding::for_label(label.as_bytes()))
+                }),
+            };
+            (declared.unwrap_or_else(|| guess(&content)), 0)
+        }
+    };
+    let declared_utf8 = xml_encoding(&content[bom_length..]).is_none_or(|label| is_utf8(&label));
+    if encoding == UTF_8 && bom_length == 0 && declared_utf8 {
+        return content;
+    }
+    log::debug!("transcoding from {}", encoding.name());
+    let (decoded, _) = encoding.decode_without_bom_handling(&content[bom_length..]);
+    declare_utf8(decoded.as_bytes())
+}
+
+/// Declares UTF-8 in the XML declaration of content decoded to UTF-8.
+fn declare_utf8(content: &[u8]) -> Vec<u8> {
+    let declaration_end = match content.windows(2).position(|window| window == b"?>") {
+        Some(end) if content.trim_ascii_start().starts_with(b"<?xml") => end,
+        _ => return content.to_vec(),
+    };
+    let declaration = String::from_utf8_lossy(&content[..declaration_end]);
+    let Some((value_start, value_end)) = attribute_value(&declaration, "encoding") else {
+        return content.to_vec();
+    };
+    let mut declared = content[..value_start].to_vec();
+    declared.extend_from_slice(b"UTF-8");
+    declared.extend_from_slice(&content[value_end..]);
+    declared
+}
+
+/// Picks between the `header` charset and the `prolog` encoding of `content`: UTF-8 if the
+/// prolog says so and the content is valid UTF-8, else the header one unless the content is not
+/// valid in it.
+fn disagreement(
+    content: &[u8],
+    header: &'static Encoding,
+    prolog: &'static Encoding,
+) -> &'static Encoding {
+    if prolog == UTF_8 && std::str::from_utf8(content).is_ok() {
+        return UTF_8;
+    }
+    if header
+        .decode_without_bom_handling_and_without_replacement(content)
+        .is_some()
+    {
+        return header;
+    }
+    log::debug!(
+        "content is not valid {}, decoding it as {}",
+        header.name(),
+        prolog.name()
+    );
+    prolog
+}
+
+fn is_utf8(label: &str) -> bool {
+    Encoding::for_label(label.as_bytes()) == Some(UTF_8)
+}
+
+/// Charset parameter of a `Content-Type` header, e.g. `text/xml; charset=iso-8859-1`.
+fn content_type_charset(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']).to_owned())
+    })
+}
+
+/// Encoding of an XML declaration, e.g. `<?xml version="1.0" encoding="iso-8859-1"?>`.
+fn xml_encoding(content: &[u8]) -> Option<String> {
+    let content = content.trim_ascii_start();
+    if !content.starts_with(b"<?xml") {
+        return None;
+    }
+    let end = content.windows(2).position(|window| window == b"?>")?;
+    let declaration = String::from_utf8_lossy(&content[..end]);
+    let (start, end) = attribute_value(&declaration, "encoding")?;
+    Some(declaration[start..end].to_owned())
+}
+
+/// Charset of the `<meta charset>` or `<meta http-equiv="Content-Type">` of an HTML page.
+fn meta_charset(content: &[u8]) -> Option<String> {
+    let head = &content[..content.len().min(META_PRESCAN)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    head.match_indices("<meta").find_map(|(start, _)| {
+        let tag = &head[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        let charset = tag.find("charset")? + "charset".len();
+        let value = tag[charset..].trim_start().strip_prefix('=')?.trim_start();
+        let value = value.trim_start_matches(['"', '\'']);
+        let end = value
+            .find(|c: char| c.is_ascii_whitespace() || matches!(c, '"' | '\'' | ';' | '/'))
+            .unwrap_or(value.len());
+        Some(value[..end].to_owned()).filter(|value| !value.is_empty())
+    })
+}
+
+fn guess(content: &[u8]) -> &'static Encoding {
+    // the content goes to mail clients, which do not run scripts
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Allow);
+    detector.feed(content, true);
+    detector.guess(None, Utf8Detection::Allow)
+}
+
+/// Range of the quoted value of attribute `name` in `tag`.
+fn attribute_value(tag: &str, name: &str) -> Option<(usize, usize)> {
+    let after_name = tag.find(name)? + name.len();
+    let value = tag[after_name..]
+        .trim_start()
+        .strip_prefix('=')?
+        .trim_start();
+    let quote = value.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    let start = tag.len() - value.len() + 1;
+    let end = start + tag[start..].find(quote)?;
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_prolog_wins_over_wrong_header() {
+        let content = "<?xml version=\"1.0\" encoding=\"UTF-8\"?><title>café</title>";
+        let decoded = to_utf8(
+            content.as_bytes().to_vec(),
+            Some("text/xml; charset=ISO-8859-1"),
+        );
+        assert_eq!(String::from_utf8(decoded).unwrap(), content);
+    }
+
+    #[test]
+    fn prolog_wins_over_header_failing_to_decode() {
+        let mut content = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><title>caf".to_vec();
+        content.extend_from_slice(b"\xe9</title>");
+        let decoded = to_utf8(content, Some("text/xml; charset=utf-8"));
+        assert_eq!(
+            String::from_utf8(decoded).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><title>café</title>"
+        );
+    }
+
+    #[test]
+    fn header_wins_when_content_matches_it() {
+        let content = b"<?xml version=\"1.0\" encoding=\"UTF-8\"?><title>caf\xe9</title>";
+        let decoded = to_utf8(content.to_vec(), Some("text/xml; charset=iso-8859-1"));
+        assert_eq!(
+            String::from_utf8(decoded).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><title>café</title>"
+        );
+    }
+}
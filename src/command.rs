@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Context, Error};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Notify,
+    time::timeout,
+};
+
+use crate::fetch::Secret;
+
+/// A program run instead of, or on the response of, an HTTP request.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Command {
+    /// the program and its arguments
+    pub run: Vec<String>,
+    /// the program is killed after this many seconds
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// working directory of the program, the current one if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// variables added to the environment of the program
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, Secret>,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+/// Lines of the error output kept to report a failed program.
+const ERROR_LINES: usize = 5;
+
+impl Command {
+    /// The command line, as shown to the user.
+    pub fn line(&self) -> String {
+        self.run.join(" ")
+    }
+
+    /// Runs the program, writing `input` to its standard input, and returns its standard output.
+    /// The program fails if it exits with a non-zero status, or outputs more than `max_size`
+    /// bytes.
+    pub async fn output(&self, input: Option<Vec<u8>>, max_size: u64) -> Result<Vec<u8>, Error> {
+        let (program, args) = self
+            .run
+            .split_first()
+            .ok_or_else(|| anyhow!("empty command"))?;
+        let mut command = tokio::process::Command::new(program);
+        command
+            .args(args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(ref dir) = self.dir {
+            command.current_dir(dir);
+        }
+        for (name, value) in &self.env {
+            command.env(name, value.resolve()?);
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("could not run `{}`", self.line()))?;
+
+        let mut stdin = child.stdin.take();
+        let mut stdout = child
+            .stdout
+            .take()
+            .expect("stdout is piped")
+            .take(max_size + 1);
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let run = async {
+            let write = async {
+                if let (Some(stdin), Some(input)) = (stdin.as_mut(), input) {
+                    // a program not reading all of its input is not an error
+                    let _ = stdin.write_all(&input).await;
+                }
+                // closing the standard input lets the program know it was all written
+                drop(stdin);
+            };
+            let too_large = Notify::new();
+            let read = async {
+                let mut output = Vec::new();
+                let read = stdout.read_to_end(&mut output).await;
+                if output.len() as u64 > max_size {
+                    // a program blocked writing the rest would never close its error output
+                    let _ = child.start_kill();
+                    too_large.notify_one();
+                }
+                read.map(|_| output)
+            };
+            let mut errors = Vec::new();
+            let read_errors = async {
+                // nor would the programs it started, still running once it is killed
+                tokio::select! {
+                    read = stderr.read_to_end(&mut errors) => read,
+                    _ = too_large.notified() => Ok(0),
+                }
+            };
+            let (_, read, read_errors) = tokio::join!(write, read, read_errors);
+            let output = read?;
+            read_errors?;
+            if output.len() as u64 > max_size {
+                return Err(anyhow!("output is larger than {} bytes", max_size));
+            }
+            let status = child.wait().await?;
+            if !status.success() {
+                let errors = String::from_utf8_lossy(&errors);
+                let lines: Vec<&str> = errors.trim_end().lines().collect();
+                let last_lines = lines[lines.len().saturating_sub(ERROR_LINES)..].join("\n");
+                if last_lines.is_empty() {
+                    return Err(anyhow!("{}", status));
+                }
+                return Err(anyhow!("{}: {}", status, last_lines));
+            }
+            Ok(output)
+        };
+        match timeout(Duration::from_secs(self.timeout_secs), run).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out after {} seconds", self.timeout_secs)),
+        }
+        .with_context(|| format!("`{}` failed", self.line()))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        Command {
+            run: vec!["sh".to_owned(), "-c".to_owned(), script.to_owned()],
+            timeout_secs: 3,
+            dir: None,
+            env: BTreeMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn input_is_piped_to_output() {
+        let output = sh("tr a-z A-Z").output(Some(b"feed".to_vec()), 10).await;
+        assert_eq!(output.unwrap(), b"FEED");
+    }
+
+    #[tokio::test]
+    async fn failure_reports_last_error_lines() {
+        let err = sh("echo first >&2; echo last >&2; exit 3")
+            .output(None, 10)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "`sh -c echo first >&2; echo last >&2; exit 3` failed: exit status: 3: first\nlast"
+        );
+    }
+
+    #[tokio::test]
+    async fn larger_output_is_rejected_without_waiting() {
+        let err = sh("head -c 100000 /dev/zero; sleep 5")
+            .output(None, 10)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).ends_with("output is larger than 10 bytes"));
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Error};
+use feed_rs::model::Feed;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use reqwest::Url;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::fetch::{self, Fetcher};
+
+/// Media types of the feeds linked by a page.
+const FEED_TYPES: [&str; 3] = [
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/feed+json",
+];
+
+/// Where sites usually publish their feed, tried when a page links to none.
+const COMMON_PATHS: [&str; 9] = [
+    "/feed",
+    "/feed/",
+    "/rss",
+    "/rss.xml",
+    "/atom.xml",
+    "/feed.xml",
+    "/index.xml",
+    "/feed.json",
+    "/blog/feed",
+];
+
+/// A feed found from the url given by the user.
+pub struct Candidate {
+    pub url: String,
+    pub feed: Box<Feed>,
+}
+
+/// Finds the feeds of `url`: itself if it is a feed, else the feeds its page links to, else
+/// the feeds found at the usual paths of its site.
+pub async fn candidates(fetcher: &Fetcher, url: &str) -> Result<Vec<Candidate>, Error> {
+    let options = fetch::Options::default();
+    let content = fetcher.body(url, &options).await?;
+    if let Ok((feed, _)) = fetcher.parse(url, &content, &options) {
+        return Ok(vec![Candidate {
+            url: url.to_owned(),
+            feed: Box::new(feed),
+        }]);
+    }
+
+    let base = Url::parse(url)?;
+    let mut urls = feed_links(&base, &String::from_utf8_lossy(&content))?;
+    if urls.is_empty() {
+        log::info!("{} links to no feed, trying usual paths", url);
+        urls = COMMON_PATHS
+            .iter()
+            .filter_map(|path| base.join(path).ok())
+            .map(|url| url.to_string())
+            .collect();
+    }
+    let mut candidates = Vec::new();
+    for url in urls {
+        if candidates
+            .iter()
+            .any(|candidate: &Candidate| candidate.url == url)
+        {
+            continue;
+        }
+        match fetcher.url(&url, &options).await {
+            Ok(feed) => candidates.push(Candidate {
+                url,
+                feed: Box::new(feed),
+            }),
+            Err(err) => log::info!("{} is not a feed: {:#}", url, err),
+        }
+    }
+    Ok(candidates)
+}
+
+/// Urls of the `<link rel="alternate">` feeds of an HTML page.
+fn feed_links(base: &Url, html: &str) -> Result<Vec<String>, Error> {
+    let links = Rc::new(RefCell::new(Vec::new()));
+    let found = links.clone();
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("link[rel][type][href]", move |el| {
+                let alternate = el.get_attribute("rel").is_some_and(|rel| {
+                    rel.split_whitespace()
+                        .any(|rel| rel.eq_ignore_ascii_case("alternate"))
+                });
+                let feed_type = el.get_attribute("type").is_some_and(|link_type| {
+                    FEED_TYPES.contains(&link_type.trim().to_ascii_lowercase().as_str())
+                });
+                if let (true, true, Some(href)) = (alternate, feed_type, el.get_attribute("href")) {
+                    let href = html_escape::decode_html_entities(&href).to_string();
+                    match base.join(&href) {
+                        Ok(url) => found.borrow_mut().push(url.to_string()),
+                        Err(err) => log::error!("could not parse feed url {}: {}", href, err),
+                    }
+                }
+                Ok(())
+            })],
+            ..RewriteStrSettings::default()
+        },
+    )
+    .map_err(|err| anyhow!("could not parse page {}: {}", base, err))?;
+    let links = links.take();
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_links_are_found() {
+        let html = r#"<html><head>
+<link rel="stylesheet" type="text/css" href="/style.css">
+<link rel="alternate" type="text/html" href="/fr/">
+<link rel="Alternate feed" type="Application/RSS+XML" href="/feed?lang=en&amp;format=rss">
+<link rel="alternate" type="application/atom+xml" href="https://cdn.example.com/atom.xml">
+<link rel="alternate" href="/no-type.xml">
+</head><body></body></html>"#;
+        let base = Url::parse("http://example.com/blog/post.html").unwrap();
+        assert_eq!(
+            feed_links(&base, html).unwrap(),
+            [
+                "http://example.com/feed?lang=en&format=rss",
+                "https://cdn.example.com/atom.xml"
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_links_are_resolved() {
+        let html = r#"<link rel="alternate" type="application/feed+json" href="feed.json">"#;
+        let base = Url::parse("http://example.com/blog/").unwrap();
+        assert_eq!(
+            feed_links(&base, html).unwrap(),
+            ["http://example.com/blog/feed.json"]
+        );
+    }
+}
@@ -1,17 +1,650 @@
-use anyhow::{Context, Error};
-use bytes::Buf;
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Utc};
 use feed_rs::model::Feed;
-use reqwest::ClientBuilder;
-
-pub async fn url(url: &str) -> Result<Feed, Error> {
-    let agent = ClientBuilder::new().build()?;
-    let resp = agent
-        .get(url)
-        .send()
-        .await
-        .with_context(|| format!("could not fetch {}", url))?;
-    let content = resp.bytes().await?;
-    let feed = feed_rs::parser::parse(content.reader())
-        .with_context(|| format!("could not parse {}", url))?;
-    Ok(feed)
+use rand::Rng;
+use reqwest::{
+    header, redirect, Client, ClientBuilder, Proxy, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{io::AsyncReadExt, time::timeout};
+
+use crate::{
+    charset,
+    command::Command,
+    repair,
+    scrape::{self, Scrape},
+    watch::{self, Watch},
+};
+
+/// Validators of a previous response, sent back so that the feed is only downloaded again when
+/// it changed.
+#[derive(Deserialize, Serialize, Default, Clone, PartialEq)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+pub struct Fetched {
+    /// the feed, `None` if it did not change since the validators were received
+    pub feed: Option<Box<Feed>>,
+    pub validators: Validators,
+    /// new url of a feed permanently redirected
+    pub moved_to: Option<String>,
+    /// the feed could only be parsed once repaired
+    pub repaired: bool,
+    /// text of a watched page, to tell what changed on next fetch
+    pub snapshot: Option<watch::Snapshot>,
+}
+
+/// A response not parsed yet.
+struct Downloaded {
+    /// the content, `None` if it did not change since the validators were received
+    body: Option<Vec<u8>>,
+    /// the `Content-Type` of an HTTP response
+    content_type: Option<String>,
+    validators: Validators,
+    moved_to: Option<String>,
+}
+
+/// The feed answered `410 Gone`, it will never be available again.
+#[derive(Debug, Clone, Copy)]
+pub struct Gone;
+
+impl Display for Gone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "feed is gone")
+    }
+}
+
+impl std::error::Error for Gone {}
+
+/// The server asked, with `Retry-After`, not to be fetched again before `until`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryLater {
+    pub until: DateTime<Utc>,
+}
+
+impl Display for RetryLater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server asked to retry after {}",
+            self.until.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+impl std::error::Error for RetryLater {}
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Url of a feed read from the standard input.
+pub const STDIN: &str = "-";
+
+/// A secret given inline, or read from an environment variable or a file so that it is not
+/// stored in the configuration.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Secret {
+    Env { env: String },
+    File { file: String },
+    Plain(String),
+}
+
+impl Secret {
+    pub fn resolve(&self) -> Result<String, Error> {
+        match self {
+            Secret::Env { env } => std::env::var(env)
+                .with_context(|| format!("could not read environment variable {}", env)),
+            Secret::File { file } => Ok(std::fs::read_to_string(file)
+                .with_context(|| format!("could not read {}", file))?
+                .trim_end_matches(['\r', '\n'])
+                .to_owned()),
+            Secret::Plain(value) => Ok(value.clone()),
+        }
+    }
+}
+
+/// How a feed is requested.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct Options {
+    /// basic authentication user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    /// bearer token authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Secret>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cookies: BTreeMap<String, Secret>,
+    /// proxy used instead of the global one, `none` to connect directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// program printing the feed, run instead of requesting the url
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<Command>,
+    /// program rewriting the feed before it is parsed, reading it on its standard input
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Command>,
+    /// selectors building the feed of an HTML page, parsed instead of a feed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scrape: Option<Scrape>,
+    /// watches the page for changes, instead of parsing a feed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watch: Option<Watch>,
+    /// repairs the mistakes making the feed invalid, `fetch.lenient` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lenient: Option<bool>,
+}
+
+/// Value of a proxy setting connecting directly, bypassing the global and environment proxies.
+pub const NO_PROXY: &str = "none";
+
+const USER_AGENT: &str = concat!("feed2imap/", env!("CARGO_PKG_VERSION"));
+
+pub struct Settings {
+    pub connect_timeout: Duration,
+    /// maximum time waiting for the next part of a response
+    pub read_timeout: Duration,
+    /// responses larger than this, in bytes, are rejected
+    pub max_size: u64,
+    /// HTTP(S) or SOCKS5 proxy of all feeds, the proxy of the environment if not set
+    pub proxy: Option<String>,
+    /// how many times a request failing temporarily is tried again
+    pub retries: u32,
+    /// delay before the first retry, doubled on each following retry
+    pub retry_delay: Duration,
+    /// longest delay waited before a retry, longer `Retry-After` are left to a later sync
+    pub max_retry_delay: Duration,
+    /// repair the mistakes making feeds invalid
+    pub lenient: bool,
+}
+
+/// Fetches feeds, sharing its HTTP client, and so its connections, between all feeds.
+pub struct Fetcher {
+    client: Client,
+    /// clients of the feeds overriding the proxy, by proxy
+    proxied: Mutex<HashMap<String, Client>>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_size: u64,
+    retries: u32,
+    retry_delay: Duration,
+    max_retry_delay: Duration,
+    lenient: bool,
+}
+
+impl Fetcher {
+    pub fn new(settings: &Settings) -> Result<Fetcher, Error> {
+        Ok(Fetcher {
+            client: build_client(settings.connect_timeout, settings.proxy.as_deref())?,
+            proxied: Mutex::new(HashMap::new()),
+            connect_timeout: settings.connect_timeout,
+            read_timeout: settings.read_timeout,
+            max_size: settings.max_size,
+            retries: settings.retries,
+            retry_delay: settings.retry_delay,
+            max_retry_delay: settings.max_retry_delay,
+            lenient: settings.lenient,
+        })
+    }
+
+    fn client(&self, options: &Options) -> Result<Client, Error> {
+        let Some(ref proxy) = options.proxy else {
+            return Ok(self.client.clone());
+        };
+        let mut proxied = self.proxied.lock().unwrap();
+        if let Some(client) = proxied.get(proxy) {
+            return Ok(client.clone());
+        }
+        let client = build_client(self.connect_timeout, Some(proxy))?;
+        proxied.insert(proxy.clone(), client.clone());
+        Ok(client)
+    }
+
+    pub async fn url(&self, url: &str, options: &Options) -> Result<Feed, Error> {
+        self.url_if_modified(url, options, &Validators::default(), None)
+            .await?
+            .feed
+            .map(|feed| *feed)
+            .ok_or_else(|| anyhow!("{} answered not modified", url))
+    }
+
+    /// Fetches the feed at `url`, unless it did not change since `validators` were received. A
+    /// watched page is compared to its `previous` snapshot.
+    pub async fn url_if_modified(
+        &self,
+        url: &str,
+        options: &Options,
+        validators: &Validators,
+        previous: Option<&watch::Snapshot>,
+    ) -> Result<Fetched, Error> {
+        let downloaded = self.download(url, options, validators).await?;
+        let mut repaired = false;
+        let mut new_snapshot = None;
+        let feed = match downloaded.body {
+            Some(mut content) => {
+                let mut content_type = downloaded.content_type.as_deref();
+                if let Some(ref filter) = options.filter {
+                    content = filter.output(Some(content), self.max_size).await?;
+                    // the filter output is not what the server described
+                    content_type = None;
+                }
+                let content = charset::to_utf8(content, content_type);
+                let feed = match (&options.watch, &options.scrape) {
+                    (Some(watch), _) => {
+                        let html = String::from_utf8_lossy(&content);
+                        let text = watch.snapshot(&html)?;
+                        let title = watch::title(url, &html);
+                        let (feed, snapshot) = watch::feed(url, &title, previous, &text);
+                        new_snapshot = Some(snapshot);
+                        feed
+                    }
+                    (None, Some(scrape)) => {
+                        scrape::feed(url, &String::from_utf8_lossy(&content), scrape)?
+                    }
+                    (None, None) => {
+                        let (feed, was_repaired) = self.parse(url, &content, options)?;
+                        repaired = was_repaired;
+                        feed
+                    }
+                };
+                Some(Box::new(feed))
+            }
+            None => None,
+        };
+        Ok(Fetched {
+            feed,
+            validators: downloaded.validators,
+            moved_to: downloaded.moved_to,
+            repaired,
+            snapshot: new_snapshot,
+        })
+    }
+
+    /// Fetches the content at `url` transcoded to UTF-8, without parsing it, e.g. a page linking
+    /// to feeds.
+    pub async fn body(&self, url: &str, options: &Options) -> Result<Vec<u8>, Error> {
+        let downloaded = self.download(url, options, &Validators::default()).await?;
+        let content = downloaded
+            .body
+            .ok_or_else(|| anyhow!("{} answered not modified", url))?;
+        Ok(charset::to_utf8(
+            content,
+            downloaded.content_type.as_deref(),
+        ))
+    }
+
+    /// Parses the feed of UTF-8 `content` downloaded from `url`, repaired if lenient, and tells
+    /// whether it was.
+    pub fn parse(
+        &self,
+        url: &str,
+        content: &[u8],
+        options: &Options,
+    ) -> Result<(Feed, bool), Error> {
+        // the parser silently drops what it can not read, a feed with mistakes is parsed once
+        // repaired rather than when it fails
+        let lenient = options.lenient.unwrap_or(self.lenient);
+        match lenient.then(|| parse_repaired(url, content)).flatten() {
+            Some(feed) => {
+                log::info!("{} parsed once repaired", url);
+                Ok((feed, true))
+            }
+            None => Ok((parse(url, content)?, false)),
+        }
+    }
+
+    async fn download(
+        &self,
+        url: &str,
+        options: &Options,
+        validators: &Validators,
+    ) -> Result<Downloaded, Error> {
+        if let Some(ref command) = options.command {
+            return Ok(Downloaded {
+                body: Some(command.output(None, self.max_size).await?),
+                content_type: None,
+                validators: Validators::default(),
+                moved_to: None,
+            });
+        }
+        if url == STDIN {
+            return self.read_stdin().await;
+        }
+        let origin = Url::parse(url).with_context(|| format!("invalid url {}", url))?;
+        if origin.scheme() == "file" {
+            return self.read_file(&origin, validators).await;
+        }
+        let client = self.client(options)?;
+        let mut current = origin.clone();
+        let mut permanent = true;
+        let mut redirects = 0;
+        let resp = loop {
+            // credentials are only sent to the host of the feed
+            let same_host = current.host_str() == origin.host_str();
+            let request = self.request(&client, current.clone(), options, validators, same_host)?;
+            let resp = self.send(&current, request).await?;
+            let status = resp.status();
+            if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+                break resp;
+            }
+            if redirects == MAX_REDIRECTS {
+                return Err(anyhow!("{} redirected too many times", url));
+            }
+            let location = resp
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| anyhow!("{} redirected without location", current))?;
+            current = current
+                .join(location)
+                .with_context(|| format!("invalid redirection to {}", location))?;
+            permanent &= matches!(
+                status,
+                StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+            );
+            redirects += 1;
+        };
+        let moved_to = if permanent && redirects > 0 {
+            log::info!("{} moved permanently to {}", url, current);
+            Some(current.to_string())
+        } else {
+            None
+        };
+
+        match resp.status() {
+            StatusCode::NOT_MODIFIED => {
+                return Ok(Downloaded {
+                    body: None,
+                    content_type: None,
+                    validators: validators.clone(),
+                    moved_to,
+                })
+            }
+            StatusCode::GONE => return Err(Gone.into()),
+            status if !status.is_success() => {
+                return Err(anyhow!("{} answered {}", url, status));
+            }
+            _ => {}
+        }
+        let header_value = |name| {
+            resp.headers()
+                .get(name)
+                .and_then(|value: &header::HeaderValue| value.to_str().ok())
+                .map(|value| value.to_owned())
+        };
+        let validators = Validators {
+            etag: header_value(header::ETAG),
+            last_modified: header_value(header::LAST_MODIFIED),
+        };
+        let content_type = header_value(header::CONTENT_TYPE);
+        let content = self
+            .read_body(resp)
+            .await
+            .with_context(|| format!("could not fetch {}", url))?;
+        Ok(Downloaded {
+            body: Some(content),
+            content_type,
+            validators,
+            moved_to,
+        })
+    }
+
+    /// Sends `request`, trying again after a delay on network errors and on the status of
+    /// temporary failures.
+    async fn send(&self, url: &Url, request: RequestBuilder) -> Result<Response, Error> {
+        let mut attempt = 0;
+        loop {
+            let retrying = attempt < self.retries;
+            let attempted = request
+                .try_clone()
+                .ok_or_else(|| anyhow!("could not build request to {}", url))?;
+            let delay = match timeout(self.read_timeout, attempted.send()).await {
+                Ok(Ok(resp)) if !is_temporary(resp.status()) => return Ok(resp),
+                Ok(Ok(resp)) => match retry_after(&resp) {
+                    Some(until) if !retrying || self.delay_until(until) > self.max_retry_delay => {
+                        return Err(RetryLater { until }.into())
+                    }
+                    Some(until) => self.delay_until(until),
+                    None if !retrying => return Ok(resp),
+                    None => self.backoff(attempt),
+                },
+                Ok(Err(err)) if retrying && (err.is_connect() || err.is_timeout()) => {
+                    log::debug!("could not fetch {}: {}", url, err);
+                    self.backoff(attempt)
+                }
+                Ok(Err(err)) => {
+                    return Err(err).with_context(|| format!("could not fetch {}", url));
+                }
+                Err(_) if retrying => self.backoff(attempt),
+                Err(_) => return Err(anyhow!("timed out fetching {}", url)),
+            };
+            attempt += 1;
+            log::info!(
+                "retrying {} in {:?} ({}/{})",
+                url,
+                delay,
+                attempt,
+                self.retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Exponential delay before the retry following `attempt`, jittered so that feeds failing
+    /// together are not retried together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_retry_delay);
+        rand::thread_rng().gen_range(delay / 2..=delay)
+    }
+
+    fn delay_until(&self, until: DateTime<Utc>) -> Duration {
+        (until - Utc::now()).to_std().unwrap_or_default()
+    }
+
+    fn request(
+        &self,
+        client: &Client,
+        url: Url,
+        options: &Options,
+        validators: &Validators,
+        authenticated: bool,
+    ) -> Result<RequestBuilder, Error> {
+        let mut request = client.get(url);
+        if let Some(ref etag) = validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        if !authenticated {
+            return Ok(request);
+        }
+        if let Some(ref username) = options.username {
+            let password = options.password.as_ref().map(Secret::resolve).transpose()?;
+            request = request.basic_auth(username, password);
+        }
+        if let Some(ref token) = options.token {
+            request = request.bearer_auth(token.resolve()?);
+        }
+        for (name, value) in &options.headers {
+            request = request.header(name, value.resolve()?);
+        }
+        if !options.cookies.is_empty() {
+            let cookies = options
+                .cookies
+                .iter()
+                .map(|(name, value)| Ok(format!("{}={}", name, value.resolve()?)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            request = request.header(header::COOKIE, cookies.join("; "));
+        }
+        Ok(request)
+    }
+
+    /// Reads a feed from the standard input, e.g. generated by another program.
+    async fn read_stdin(&self) -> Result<Downloaded, Error> {
+        let mut content = Vec::new();
+        tokio::io::stdin()
+            .take(self.max_size + 1)
+            .read_to_end(&mut content)
+            .await
+            .context("could not read standard input")?;
+        if content.len() as u64 > self.max_size {
+            return Err(anyhow!(
+                "standard input is larger than {} bytes",
+                self.max_size
+            ));
+        }
+        Ok(Downloaded {
+            body: Some(content),
+            content_type: None,
+            validators: Validators::default(),
+            moved_to: None,
+        })
+    }
+
+    /// Reads a local feed, unless its modification time is the one of `validators`.
+    async fn read_file(&self, url: &Url, validators: &Validators) -> Result<Downloaded, Error> {
+        let path = url
+            .to_file_path()
+            .map_err(|_| anyhow!("invalid file url {}", url))?;
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .with_context(|| format!("could not read {}", path.display()))?;
+        if metadata.len() > self.max_size {
+            return Err(anyhow!(
+                "{} is larger than {} bytes",
+                path.display(),
+                self.max_size
+            ));
+        }
+        let last_modified = metadata
+            .modified()
+            .ok()
+            .map(|modified| DateTime::<Utc>::from(modified).to_rfc2822());
+        let file_validators = Validators {
+            etag: None,
+            last_modified,
+        };
+        if file_validators.last_modified.is_some() && file_validators == *validators {
+            return Ok(Downloaded {
+                body: None,
+                content_type: None,
+                validators: file_validators,
+                moved_to: None,
+            });
+        }
+        let content = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Ok(Downloaded {
+            body: Some(content),
+            content_type: None,
+            validators: file_validators,
+            moved_to: None,
+        })
+    }
+
+    /// Reads the body of `resp`, giving up when it stalls or grows over the maximum size.
+    async fn read_body(&self, mut resp: Response) -> Result<Vec<u8>, Error> {
+        if resp
+            .content_length()
+            .is_some_and(|length| length > self.max_size)
+        {
+            return Err(anyhow!("response is larger than {} bytes", self.max_size));
+        }
+        let mut content = Vec::new();
+        while let Some(chunk) = timeout(self.read_timeout, resp.chunk())
+            .await
+            .map_err(|_| anyhow!("timed out reading response"))??
+        {
+            if content.len() as u64 + chunk.len() as u64 > self.max_size {
+                return Err(anyhow!("response is larger than {} bytes", self.max_size));
+            }
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content)
+    }
+}
+
+/// Parses the feed downloaded from `url`.
+fn parse(url: &str, content: &[u8]) -> Result<Feed, Error> {
+    feed_rs::parser::parse(content).with_context(|| format!("could not parse {}", url))
+}
+
+/// Parses the feed downloaded from `url` once repaired, `None` if it had no mistake to repair or
+/// can still not be parsed.
+fn parse_repaired(url: &str, content: &[u8]) -> Option<Feed> {
+    let content = repair::repair(content)?;
+    parse(url, &content).ok()
+}
+
+/// Statuses of servers failing temporarily, worth trying again.
+fn is_temporary(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// When a rate limited (429) or unavailable (503) server may be fetched again, from its
+/// `Retry-After` header given in seconds or as a date.
+fn retry_after(resp: &Response) -> Option<DateTime<Utc>> {
+    if !matches!(
+        resp.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    let value = resp
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse::<u32>() {
+        Ok(secs) => Some(Utc::now() + chrono::Duration::seconds(secs.into())),
+        Err(_) => DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.with_timezone(&Utc)),
+    }
+}
+
+fn build_client(connect_timeout: Duration, proxy: Option<&str>) -> Result<Client, Error> {
+    // redirections are followed by the fetcher, to tell permanent ones
+    let mut builder = ClientBuilder::new()
+        .redirect(redirect::Policy::none())
+        .user_agent(USER_AGENT)
+        .connect_timeout(connect_timeout)
+        .gzip(true)
+        .brotli(true)
+        .deflate(true);
+    match proxy {
+        Some(NO_PROXY) => builder = builder.no_proxy(),
+        Some(proxy) => {
+            builder = builder
+                .proxy(Proxy::all(proxy).with_context(|| format!("invalid proxy {}", proxy))?)
+        }
+        None => {}
+    }
+    Ok(builder.build()?)
 }
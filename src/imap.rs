@@ -1,104 +1,352 @@
+use crate::state::{self, FolderState};
 use crate::sync;
-use anyhow::Error;
-use async_imap::types::Fetch;
-use futures::StreamExt;
+use anyhow::{anyhow, Context, Error};
+use async_imap::types::{Fetch, Name};
+use futures::{StreamExt, TryStreamExt};
 use mail_parser;
-use std::{collections::BTreeSet, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::{net::TcpStream, sync::Mutex};
 use tokio_rustls::rustls::pki_types::ServerName;
 use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 use tokio_rustls::{client::TlsStream, TlsConnector};
 use webpki_roots;
 
+/// Transport security used to reach the mail server, by IMAP or by SMTP.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// TLS from the first byte, usually on port 993 for IMAP and 465 for SMTP
+    #[default]
+    Tls,
+    /// plaintext connection upgraded with STARTTLS, usually on port 143 for IMAP and 587 for
+    /// SMTP
+    StartTls,
+    /// no encryption at all, only meant for local test servers
+    Plain,
+}
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug> Stream for T {}
+
 pub struct Client {
-    imap: async_imap::Session<TlsStream<TcpStream>>,
+    imap: async_imap::Session<Box<dyn Stream>>,
+    /// hierarchy delimiter of the server, `None` if the namespace is flat
+    delimiter: Option<String>,
+    /// encoded names of the mailboxes known to exist, loaded on first use
+    mailboxes: Option<HashSet<String>>,
 }
 
-pub async fn client(username: &str, password: &str) -> Result<Client, Error> {
-    let imap_server = "mail.infomaniak.com";
-    let imap_addr = (imap_server, 993);
-    let tcp_stream = TcpStream::connect(imap_addr).await?;
+pub async fn client(
+    host: &str,
+    port: u16,
+    security: Security,
+    username: &str,
+    password: &str,
+) -> Result<Client, Error> {
+    let tcp_stream = TcpStream::connect((host, port))
+        .await
+        .with_context(|| format!("could not connect to {}:{}", host, port))?;
+    let stream: Box<dyn Stream> = match security {
+        Security::Tls => Box::new(tls_connect(host, tcp_stream).await?),
+        Security::StartTls => {
+            let mut client = async_imap::Client::new(tcp_stream);
+            client
+                .read_response()
+                .await
+                .ok_or_else(|| anyhow!("{} closed the connection before greeting", host))??;
+            client.run_command_and_check_ok("STARTTLS", None).await?;
+            Box::new(tls_connect(host, client.into_inner()).await?)
+        }
+        Security::Plain => {
+            log::warn!("connecting to {}:{} without encryption", host, port);
+            Box::new(tcp_stream)
+        }
+    };
+    let client = async_imap::Client::new(stream);
+    let mut session = client.login(username, password).await.map_err(|e| e.0)?;
+    let delimiter = hierarchy_delimiter(&mut session).await?;
+    log::debug!("{} hierarchy delimiter is {:?}", host, delimiter);
+    Ok(Client {
+        imap: session,
+        delimiter,
+        mailboxes: None,
+    })
+}
+
+async fn hierarchy_delimiter(
+    session: &mut async_imap::Session<Box<dyn Stream>>,
+) -> Result<Option<String>, Error> {
+    let names: Vec<Name> = session
+        .list(None, Some("\"\""))
+        .await?
+        .try_collect()
+        .await?;
+    Ok(names
+        .first()
+        .and_then(|name| name.delimiter())
+        .map(|delimiter| delimiter.to_owned()))
+}
+
+async fn tls_connect(host: &str, tcp_stream: TcpStream) -> Result<TlsStream<TcpStream>, Error> {
     let mut root_cert_store = RootCertStore::empty();
     root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     let config = ClientConfig::builder()
         .with_root_certificates(root_cert_store)
         .with_no_client_auth();
     let tls = TlsConnector::from(Arc::new(config));
-    let server_name = ServerName::try_from(imap_server)?;
-    let tls_stream = tls.connect(server_name, tcp_stream).await?;
-    let client = async_imap::Client::new(tls_stream);
-    let session = client.login(username, password).await.map_err(|e| e.0)?;
-    Ok(Client { imap: session })
+    let server_name = ServerName::try_from(host.to_owned())?;
+    let tls_stream = tls
+        .connect(server_name, tcp_stream)
+        .await
+        .with_context(|| format!("TLS handshake with {} failed", host))?;
+    Ok(tls_stream)
 }
 
 impl Client {
     pub async fn append(&mut self, mail: &Vec<u8>, folder: &str) -> Result<(), Error> {
-        let _result = self.imap.select(folder).await?;
-        let _result = self.imap.append(folder, mail).await?;
+        let mailbox = self.ensure_mailbox(folder).await?;
+        self.imap
+            .append(&mailbox, mail)
+            .await
+            .with_context(|| format!("could not append to {}", folder))?;
         Ok(())
     }
 
+    /// Translates a `Feeds/Tech` style folder into the server mailbox name, using its hierarchy
+    /// delimiter and modified UTF-7 encoding.
+    fn mailbox_name(&self, folder: &str) -> String {
+        let segments = folder
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(encode_modified_utf7);
+        match self.delimiter {
+            Some(ref delimiter) => segments.collect::<Vec<_>>().join(delimiter),
+            None => encode_modified_utf7(folder),
+        }
+    }
+
+    async fn mailbox_exists(&mut self, mailbox: &str) -> Result<bool, Error> {
+        if mailbox.eq_ignore_ascii_case("INBOX") {
+            return Ok(true);
+        }
+        if self.mailboxes.is_none() {
+            let names: Vec<Name> = self.imap.list(None, Some("*")).await?.try_collect().await?;
+            self.mailboxes = Some(names.iter().map(|name| name.name().to_owned()).collect());
+        }
+        Ok(self
+            .mailboxes
+            .as_ref()
+            .is_some_and(|mailboxes| mailboxes.contains(mailbox)))
+    }
+
+    /// Creates and subscribes to the mailbox of `folder` and its parents when they do not exist
+    /// yet, and returns its name on the server.
+    async fn ensure_mailbox(&mut self, folder: &str) -> Result<String, Error> {
+        let mailbox = self.mailbox_name(folder);
+        if self.mailbox_exists(&mailbox).await? {
+            return Ok(mailbox);
+        }
+        let segments: Vec<&str> = folder
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        // in a flat namespace, `Feeds/Tech` is a mailbox of its own without parents
+        let paths: Vec<String> = match self.delimiter {
+            Some(_) => (1..=segments.len())
+                .map(|length| segments[..length].join("/"))
+                .collect(),
+            None => vec![folder.to_owned()],
+        };
+        for path in paths {
+            let parent = self.mailbox_name(&path);
+            if self.mailbox_exists(&parent).await? {
+                continue;
+            }
+            log::info!("creating mailbox {}", parent);
+            self.imap
+                .create(&parent)
+                .await
+                .with_context(|| format!("could not create mailbox {}", parent))?;
+            if let Err(err) = self.imap.subscribe(&parent).await {
+                log::warn!("could not subscribe to {}: {}", parent, err);
+            }
+            if let Some(ref mut mailboxes) = self.mailboxes {
+                mailboxes.insert(parent);
+            }
+        }
+        Ok(mailbox)
+    }
+
     pub async fn logout(&mut self) -> Result<(), Error> {
         let _result = self.imap.logout().await?;
         Ok(())
     }
 
-    pub async fn list_message_ids(&mut self, folder: &str) -> Result<BTreeSet<String>, Error> {
-        let mailbox = self.imap.examine(folder).await?;
+    /// Lists the message ids of `folder`, only fetching the messages added since `cache` was
+    /// filled, unless the folder UIDVALIDITY changed.
+    pub async fn list_message_ids(
+        &mut self,
+        folder: &str,
+        cache: FolderState,
+    ) -> Result<FolderState, Error> {
+        let name = self.mailbox_name(folder);
+        if !self.mailbox_exists(&name).await? {
+            log::debug!("{} mailbox does not exist yet", folder);
+            return Ok(FolderState::default());
+        }
+        let mailbox = self.imap.examine(&name).await?;
         log::debug!("there is {} in {} mailbox", mailbox.exists, folder);
+        let mut cache =
+            if mailbox.uid_validity.is_some() && mailbox.uid_validity == cache.uid_validity {
+                cache
+            } else {
+                if cache.uid_validity.is_some() {
+                    log::info!("{} UIDVALIDITY changed, rescanning", folder);
+                }
+                FolderState {
+                    uid_validity: mailbox.uid_validity,
+                    ..FolderState::default()
+                }
+            };
+        if mailbox.exists == 0 {
+            return Ok(cache);
+        }
+        if mailbox
+            .uid_next
+            .is_some_and(|uid_next| uid_next <= cache.last_uid + 1)
+        {
+            log::debug!("no new message in {} since uid {}", folder, cache.last_uid);
+            return Ok(cache);
+        }
 
         let stream = self
             .imap
-            .fetch("1:*", "(FLAGS BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])")
+            .uid_fetch(
+                format!("{}:*", cache.last_uid + 1),
+                "(UID BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])",
+            )
             .await?;
         let fetches: Result<Vec<Fetch>, _> = stream.collect::<Vec<_>>().await.into_iter().collect();
         let parser = mail_parser::MessageParser::default();
-        let ids = fetches?
-            .iter()
-            .filter_map(|fetch| fetch.header())
-            .filter_map(|header| parser.parse_headers(header))
-            .filter_map(|msg| {
-                if let Some(header) = msg.header("MESSAGE-ID") {
-                    if let Some(id) = header.clone().into_text() {
-                        Some(id.into_owned())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect();
-        Ok(ids)
+        let last_uid = cache.last_uid;
+        // `n:*` always matches the last message, even when its uid is lower than n
+        for fetch in fetches?.iter().filter(|fetch| fetch.uid > Some(last_uid)) {
+            cache.last_uid = cache.last_uid.max(fetch.uid.unwrap_or_default());
+            if let Some(id) = fetch
+                .header()
+                .and_then(|header| parser.parse_headers(header))
+                .and_then(|msg| Some(msg.header("MESSAGE-ID")?.clone().into_text()?.into_owned()))
+            {
+                cache.ids.insert(id);
+            }
+        }
+        Ok(cache)
     }
 }
 
 pub struct InnerOutput {
     client: Mutex<Client>,
-    ids: BTreeSet<String>,
+    ids: Mutex<HashMap<String, BTreeSet<String>>>,
     folder: String,
+    store: state::Store,
 }
 
 pub type Output = Arc<InnerOutput>;
 
-pub async fn new_output(mut client: Client, folder: &str) -> Result<Output, Error> {
-    let ids = client.list_message_ids(&folder).await?;
-    Ok(Arc::new(InnerOutput {
+/// Creates an output appending to `folder` by default, caching the message ids of the folders
+/// in `store`.
+pub fn new_output(client: Client, folder: &str, store: state::Store) -> Output {
+    Arc::new(InnerOutput {
         client: Mutex::new(client),
-        ids,
+        ids: Mutex::new(HashMap::new()),
         folder: folder.to_string(),
-    }))
+        store,
+    })
 }
 
 impl sync::Output for Output {
-    fn contains(&self, id: &str) -> bool {
-        return self.ids.contains(id);
+    async fn contains(&self, _feed: &str, id: &str, folder: Option<&str>) -> Result<bool, Error> {
+        let folder = folder.unwrap_or(&self.folder);
+        let mut ids = self.ids.lock().await;
+        if !ids.contains_key(folder) {
+            let cache = self.store.folder(folder).await;
+            let mut imap_client = self.client.lock().await;
+            let cache = imap_client.list_message_ids(folder, cache).await?;
+            ids.insert(folder.to_string(), cache.ids.clone());
+            self.store.set_folder(folder, cache).await;
+        }
+        Ok(ids[folder].contains(id))
     }
-
-    async fn append(&self, mail: &Vec<u8>, folder: Option<&str>) -> Result<(), Error> {
+    async fn append(
+        &self,
+        _feed: &str,
+        _id: &str,
+        mail: &Vec<u8>,
+        folder: Option<&str>,
+    ) -> Result<(), Error> {
         let mut imap_client = self.client.lock().await;
         Ok(imap_client
             .append(mail, folder.unwrap_or(&self.folder))
             .await?)
     }
 }
+
+/// Encodes a mailbox name segment with the modified UTF-7 of RFC 3501, section 5.1.3.
+pub(crate) fn encode_modified_utf7(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    let mut pending = Vec::new();
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush_modified_base64(&mut encoded, &mut pending);
+            if c == '&' {
+                encoded.push_str("&-");
+            } else {
+                encoded.push(c);
+            }
+        } else {
+            let mut buf = [0u16; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+    flush_modified_base64(&mut encoded, &mut pending);
+    encoded
+}
+
+fn flush_modified_base64(encoded: &mut String, pending: &mut Vec<u16>) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+    if pending.is_empty() {
+        return;
+    }
+    let bytes: Vec<u8> = pending.drain(..).flat_map(u16::to_be_bytes).collect();
+    encoded.push('&');
+    for chunk in bytes.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3f] as char);
+        }
+    }
+    encoded.push('-');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modified_utf7() {
+        assert_eq!(encode_modified_utf7("INBOX"), "INBOX");
+        assert_eq!(encode_modified_utf7("Entwürfe"), "Entw&APw-rfe");
+        assert_eq!(encode_modified_utf7("Tom & Jerry"), "Tom &- Jerry");
+        assert_eq!(encode_modified_utf7("日本語"), "&ZeVnLIqe-");
+        assert_eq!(encode_modified_utf7("🦀"), "&2D7dgA-");
+    }
+}
@@ -1,4 +1,15 @@
+pub mod charset;
+pub mod command;
+pub mod discover;
 pub mod fetch;
 pub mod imap;
+pub mod maildir;
+pub mod mbox;
+pub mod repair;
+pub mod scheduler;
+pub mod scrape;
+pub mod smtp;
+pub mod state;
 pub mod sync;
 pub mod transform;
+pub mod watch;
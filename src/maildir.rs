@@ -0,0 +1,175 @@
+use crate::{imap::encode_modified_utf7, sync};
+use anyhow::{anyhow, Context, Error};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+pub struct InnerOutput {
+    root: PathBuf,
+    folder: String,
+    ids: Mutex<HashMap<String, BTreeSet<String>>>,
+    deliveries: AtomicU64,
+}
+
+pub type Output = Arc<InnerOutput>;
+
+/// Creates an output delivering into the Maildir++ tree at `root`, to `folder` by default.
+pub fn new_output<P: AsRef<Path>>(root: P, folder: &str) -> Output {
+    Arc::new(InnerOutput {
+        root: root.as_ref().to_owned(),
+        folder: folder.to_string(),
+        ids: Mutex::new(HashMap::new()),
+        deliveries: AtomicU64::new(0),
+    })
+}
+
+impl InnerOutput {
+    /// Maps a `Feeds/Tech` style folder to its Maildir++ directory, `<root>/.Feeds.Tech`, its
+    /// names encoded in modified UTF-7 as IMAP servers expect. The `INBOX` folder is the root
+    /// Maildir itself.
+    fn folder_path(&self, folder: &str) -> Result<PathBuf, Error> {
+        let segments: Vec<&str> = folder
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        if segments.is_empty() || (segments.len() == 1 && segments[0].eq_ignore_ascii_case("INBOX"))
+        {
+            return Ok(self.root.clone());
+        }
+        // `.` separates the folders of a Maildir++ tree
+        if segments.iter().any(|segment| segment.contains('.')) {
+            return Err(anyhow!(
+                "folder {} can not contain `.` in a Maildir++ tree",
+                folder
+            ));
+        }
+        let names: Vec<String> = segments
+            .iter()
+            .map(|segment| encode_modified_utf7(segment))
+            .collect();
+        Ok(self.root.join(format!(".{}", names.join("."))))
+    }
+
+    async fn create_folder(&self, path: &Path) -> Result<(), Error> {
+        for sub in ["tmp", "new", "cur"] {
+            let dir = path.join(sub);
+            fs::create_dir_all(&dir)
+                .await
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        if path != self.root {
+            fs::write(path.join("maildirfolder"), b"").await?;
+        }
+        Ok(())
+    }
+
+    /// Unique file name of a new message, following the Maildir conventions.
+    fn unique_name(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let hostname = std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .unwrap_or_else(|_| "localhost".to_owned())
+            .replace('/', "\\057")
+            .replace(':', "\\072");
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            self.deliveries.fetch_add(1, Ordering::Relaxed),
+            hostname
+        )
+    }
+}
+
+async fn list_message_ids(path: &Path) -> Result<BTreeSet<String>, Error> {
+    let parser = mail_parser::MessageParser::default();
+    let mut ids = BTreeSet::new();
+    for sub in ["new", "cur"] {
+        let dir = path.join(sub);
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not list {}", dir.display()))
+            }
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let headers = read_headers(&entry.path()).await?;
+            if let Some(id) = parser
+                .parse_headers(&headers[..])
+                .and_then(|msg| Some(msg.header("MESSAGE-ID")?.clone().into_text()?.into_owned()))
+            {
+                ids.insert(id);
+            }
+        }
+    }
+    log::debug!("there is {} messages in {}", ids.len(), path.display());
+    Ok(ids)
+}
+
+/// Reads the headers of the message at `path`, up to the blank line starting its body.
+async fn read_headers(path: &Path) -> Result<Vec<u8>, Error> {
+    let file = fs::File::open(path)
+        .await
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut headers = Vec::new();
+    loop {
+        let start = headers.len();
+        if reader.read_until(b'\n', &mut headers).await? == 0 {
+            break;
+        }
+        if headers[start..].iter().all(|b| b.is_ascii_whitespace()) {
+            break;
+        }
+    }
+    Ok(headers)
+}
+
+impl sync::Output for Output {
+    async fn contains(&self, _feed: &str, id: &str, folder: Option<&str>) -> Result<bool, Error> {
+        let folder = folder.unwrap_or(&self.folder);
+        let mut ids = self.ids.lock().await;
+        if !ids.contains_key(folder) {
+            let folder_ids = list_message_ids(&self.folder_path(folder)?).await?;
+            ids.insert(folder.to_string(), folder_ids);
+        }
+        Ok(ids[folder].contains(id))
+    }
+
+    async fn append(
+        &self,
+        _feed: &str,
+        _id: &str,
+        mail: &Vec<u8>,
+        folder: Option<&str>,
+    ) -> Result<(), Error> {
+        let path = self.folder_path(folder.unwrap_or(&self.folder))?;
+        self.create_folder(&path).await?;
+        let name = self.unique_name();
+        let tmp_path = path.join("tmp").join(&name);
+        let mut file = fs::File::create(&tmp_path)
+            .await
+            .with_context(|| format!("could not create {}", tmp_path.display()))?;
+        file.write_all(mail).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, path.join("new").join(&name))
+            .await
+            .with_context(|| format!("could not deliver {}", tmp_path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::sync;
+use anyhow::{Context, Error};
+use chrono::Utc;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+pub struct InnerOutput {
+    path: PathBuf,
+    per_feed: bool,
+    ids: Mutex<HashMap<PathBuf, BTreeSet<String>>>,
+}
+
+pub type Output = Arc<InnerOutput>;
+
+/// Creates an output appending messages to the mbox file at `path`, or, when `per_feed` is set,
+/// to one mbox file per feed in the `path` directory.
+pub fn new_output<P: AsRef<Path>>(path: P, per_feed: bool) -> Output {
+    Arc::new(InnerOutput {
+        path: path.as_ref().to_owned(),
+        per_feed,
+        ids: Mutex::new(HashMap::new()),
+    })
+}
+
+impl InnerOutput {
+    fn file_path(&self, feed: &str, folder: Option<&str>) -> PathBuf {
+        file_path(&self.path, self.per_feed, feed, folder)
+    }
+}
+
+/// File receiving the entries of `feed` in the output at `path`: `path` itself, or, when
+/// `per_feed` is set, a file named after the feed folder if any, or its url.
+pub fn file_path(path: &Path, per_feed: bool, feed: &str, folder: Option<&str>) -> PathBuf {
+    if !per_feed {
+        return path.to_owned();
+    }
+    let name: String = folder
+        .map(|folder| folder.replace('/', "."))
+        .unwrap_or_else(|| {
+            feed.trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect()
+        });
+    path.join(format!("{}.mbox", name))
+}
+
+async fn list_message_ids(path: &Path) -> Result<BTreeSet<String>, Error> {
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(err) => return Err(err).with_context(|| format!("could not read {}", path.display())),
+    };
+    let parser = mail_parser::MessageParser::default();
+    let mut ids = BTreeSet::new();
+    let mut headers: Option<Vec<u8>> = None;
+    let mut previous_blank = true;
+    for line in content.split_inclusive(|b| *b == b'\n') {
+        let blank = line.iter().all(|b| b.is_ascii_whitespace());
+        if previous_blank && line.starts_with(b"From ") {
+            headers = Some(Vec::new());
+        } else if let Some(ref mut header_lines) = headers {
+            if blank {
+                if let Some(id) = parser.parse_headers(&header_lines[..]).and_then(|msg| {
+                    Some(msg.header("MESSAGE-ID")?.clone().into_text()?.into_owned())
+                }) {
+                    ids.insert(id);
+                }
+                headers = None;
+            } else {
+                header_lines.extend_from_slice(line);
+            }
+        }
+        previous_blank = blank;
+    }
+    log::debug!("there is {} messages in {}", ids.len(), path.display());
+    Ok(ids)
+}
+
+/// Formats `mail` as a mboxrd message: a `From ` separator line, LF line endings, and `From `
+/// lines of the body quoted with an additional `>`.
+fn format_message(mail: &[u8]) -> Vec<u8> {
+    let mut message = format!(
+        "From feed2imap@localhost {}\n",
+        Utc::now().format("%a %b %e %H:%M:%S %Y")
+    )
+    .into_bytes();
+    for line in mail.split_inclusive(|b| *b == b'\n') {
+        let line = line
+            .strip_suffix(b"\r\n")
+            .or_else(|| line.strip_suffix(b"\n"))
+            .unwrap_or(line);
+        let unquoted = &line[line.iter().take_while(|b| **b == b'>').count()..];
+        if unquoted.starts_with(b"From ") {
+            message.push(b'>');
+        }
+        message.extend_from_slice(line);
+        message.push(b'\n');
+    }
+    message.push(b'\n');
+    message
+}
+
+impl sync::Output for Output {
+    async fn contains(&self, feed: &str, id: &str, folder: Option<&str>) -> Result<bool, Error> {
+        let path = self.file_path(feed, folder);
+        let mut ids = self.ids.lock().await;
+        if !ids.contains_key(&path) {
+            let file_ids = list_message_ids(&path).await?;
+            ids.insert(path.clone(), file_ids);
+        }
+        Ok(ids[&path].contains(id))
+    }
+
+    async fn append(
+        &self,
+        feed: &str,
+        _id: &str,
+        mail: &Vec<u8>,
+        folder: Option<&str>,
+    ) -> Result<(), Error> {
+        let path = self.file_path(feed, folder);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        // writes are serialized so that messages do not interleave
+        let _ids = self.ids.lock().await;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("could not open {}", path.display()))?;
+        file.write_all(&format_message(mail)).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIL: &[u8] = b"Message-ID: <1@example.com>\r\nSubject: quoting\r\n\r\nFrom here\r\n>From there\r\nnot From\r\n";
+
+    #[test]
+    fn from_lines_are_quoted() {
+        let message = format_message(MAIL);
+        let body = message.split(|b| *b == b'\n').skip(4).collect::<Vec<_>>();
+        assert_eq!(
+            body[..3],
+            [&b">From here"[..], b">>From there", b"not From"]
+        );
+        assert!(message.starts_with(b"From feed2imap@localhost "));
+        assert!(message.ends_with(b"\n\n"));
+    }
+
+    #[tokio::test]
+    async fn message_ids_are_listed_back() {
+        let dir = std::env::temp_dir().join(format!("feed2imap-mbox-{}", std::process::id()));
+        let output = new_output(&dir, true);
+        // once quoted, the body can not be read as a message of its own
+        let other =
+            b"Message-ID: <2@example.com>\r\n\r\nFrom the start\r\nMessage-ID: <3@example.com>\r\n";
+        sync::Output::append(&output, "feed", "", &MAIL.to_vec(), Some("Feeds"))
+            .await
+            .unwrap();
+        sync::Output::append(&output, "feed", "", &other.to_vec(), Some("Feeds"))
+            .await
+            .unwrap();
+
+        let ids = list_message_ids(&dir.join("Feeds.mbox")).await.unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            ids.into_iter().collect::<Vec<_>>(),
+            ["1@example.com", "2@example.com"]
+        );
+    }
+}
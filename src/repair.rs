@@ -0,0 +1,121 @@
+/// Entities predefined by XML, all other named entities must be declared.
+const XML_ENTITIES: [&str; 5] = ["amp", "lt", "gt", "quot", "apos"];
+
+const CDATA_START: &[u8] = b"<![CDATA[";
+const CDATA_END: &[u8] = b"]]>";
+
+/// Repairs the usual mistakes making a feed of UTF-8 `content` invalid XML: control characters
+/// and HTML entities. Leading white spaces are removed too, but are not a mistake the parser
+/// cares about. Returns `None` if no mistake was found.
+pub fn repair(content: &[u8]) -> Option<Vec<u8>> {
+    let content = content.trim_ascii_start();
+    let repaired = replace_entities(&remove_control_chars(content));
+    if repaired == content {
+        None
+    } else {
+        Some(repaired)
+    }
+}
+
+/// Removes the control characters XML forbids, e.g. left by a copy and paste.
+fn remove_control_chars(content: &[u8]) -> Vec<u8> {
+    // bytes of multi-byte UTF-8 characters are all above 0x7F, these are always control chars
+    content
+        .iter()
+        .copied()
+        .filter(|byte| *byte >= 0x20 || matches!(byte, b'\t' | b'\n' | b'\r'))
+        .collect()
+}
+
+/// Replaces the HTML entities, like `&nbsp;`, by character references, and escapes the `&` not
+/// starting an entity. CDATA sections are left untouched.
+fn replace_entities(content: &[u8]) -> Vec<u8> {
+    let mut replaced = Vec::with_capacity(content.len());
+    let mut i = 0;
+    while i < content.len() {
+        if content[i..].starts_with(CDATA_START) {
+            let end = find(&content[i..], CDATA_END)
+                .map(|end| i + end + CDATA_END.len())
+                .unwrap_or(content.len());
+            replaced.extend_from_slice(&content[i..end]);
+            i = end;
+            continue;
+        }
+        if content[i] != b'&' {
+            replaced.push(content[i]);
+            i += 1;
+            continue;
+        }
+        let name_end = content[i + 1..]
+            .iter()
+            .position(|byte| !byte.is_ascii_alphanumeric() && *byte != b'#')
+            .map(|end| i + 1 + end);
+        match name_end {
+            Some(end) if content[end] == b';' && end > i + 1 => {
+                let name = String::from_utf8_lossy(&content[i + 1..end]);
+                if name.starts_with('#') || XML_ENTITIES.contains(&name.as_ref()) {
+                    replaced.extend_from_slice(&content[i..=end]);
+                } else {
+                    let entity = format!("&{};", name);
+                    let decoded = html_escape::decode_html_entities(&entity);
+                    if decoded == entity {
+                        // unknown entity, kept as text
+                        replaced.extend_from_slice(b"&amp;");
+                        replaced.extend_from_slice(&content[i + 1..=end]);
+                    } else {
+                        for c in decoded.chars() {
+                            replaced.extend_from_slice(format!("&#{};", c as u32).as_bytes());
+                        }
+                    }
+                }
+                i = end + 1;
+            }
+            _ => {
+                replaced.extend_from_slice(b"&amp;");
+                i += 1;
+            }
+        }
+    }
+    replaced
+}
+
+fn find(content: &[u8], needle: &[u8]) -> Option<usize> {
+    content
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_feed_is_left_alone() {
+        let feed =
+            b"<?xml version=\"1.0\"?><rss><title>a &amp; b &#233; <![CDATA[&nbsp;]]></title></rss>";
+        assert_eq!(repair(feed), None);
+    }
+
+    #[test]
+    fn html_entities_are_replaced() {
+        let repaired = repair(b"<title>a&nbsp;b &eacute; R&D &bogus; &lt;</title>").unwrap();
+        assert_eq!(
+            String::from_utf8(repaired).unwrap(),
+            "<title>a&#160;b &#233; R&amp;D &amp;bogus; &lt;</title>"
+        );
+    }
+
+    #[test]
+    fn control_chars_and_leading_spaces_are_removed() {
+        let repaired = repair(b"\n  <?xml version=\"1.0\"?><title>a\x0bb\x00</title>\n").unwrap();
+        assert_eq!(
+            String::from_utf8(repaired).unwrap(),
+            "<?xml version=\"1.0\"?><title>ab</title>\n"
+        );
+    }
+
+    #[test]
+    fn leading_spaces_alone_are_not_repaired() {
+        assert_eq!(repair(b"\n  <rss><title>a</title></rss>"), None);
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::Error;
+use reqwest::Url;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Limits how many feeds are fetched at once, overall and per host, and spaces out the
+/// requests made to a same host.
+pub struct Scheduler {
+    global: Arc<Semaphore>,
+    per_host: usize,
+    delay: Duration,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+struct Host {
+    semaphore: Arc<Semaphore>,
+    /// when the next request to this host may start
+    next: Mutex<Instant>,
+}
+
+/// Allows a fetch until dropped.
+pub struct Permit {
+    _host: Option<OwnedSemaphorePermit>,
+    _global: OwnedSemaphorePermit,
+}
+
+impl Scheduler {
+    pub fn new(concurrency: usize, per_host: usize, delay: Duration) -> Scheduler {
+        Scheduler {
+            global: Arc::new(Semaphore::new(concurrency.max(1))),
+            per_host: per_host.max(1),
+            delay,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a feed from `host` may be fetched. Feeds without host, e.g. local files or
+    /// commands, are only limited by the overall concurrency.
+    pub async fn acquire(&self, host: Option<&str>) -> Result<Permit, Error> {
+        let Some(host) = host else {
+            return Ok(Permit {
+                _host: None,
+                _global: self.global.clone().acquire_owned().await?,
+            });
+        };
+        let host = {
+            let mut hosts = self.hosts.lock().await;
+            hosts
+                .entry(host.to_owned())
+                .or_insert_with(|| {
+                    Arc::new(Host {
+                        semaphore: Arc::new(Semaphore::new(self.per_host)),
+                        next: Mutex::new(Instant::now()),
+                    })
+                })
+                .clone()
+        };
+        // waiting for the host first, and its delay, leaves the global slots to the other hosts
+        let host_permit = host.semaphore.clone().acquire_owned().await?;
+        let mut next = host.next.lock().await;
+        tokio::time::sleep_until(*next).await;
+        let global_permit = self.global.clone().acquire_owned().await?;
+        // the delay is counted from when the request can start
+        *next = Instant::now() + self.delay;
+        drop(next);
+        Ok(Permit {
+            _host: Some(host_permit),
+            _global: global_permit,
+        })
+    }
+}
+
+/// Host of `url`, `None` if it is not fetched over HTTP.
+pub fn host_of(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .and_then(|url| url.host_str().map(|host| host.to_owned()))
+}
@@ -0,0 +1,274 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use feed_rs::model::{Content, Entry, Feed, FeedType, Link, Text};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+
+/// CSS selectors picking the entries of a page without feed. Inside an item, the first element
+/// matched by a selector is used.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Scrape {
+    /// elements holding one entry each
+    pub item: String,
+    /// text of the entry title, the text of the link if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// element whose `href` links to the entry, the first link of the item if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    /// element whose `datetime` attribute or text is the entry date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// format of the dates, e.g. `%m/%d/%Y`, tried before RFC 3339, RFC 2822 and the usual
+    /// formats of `DATE_TIME_FORMATS` and `DATE_FORMATS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_format: Option<String>,
+    /// HTML content of the entry, the whole item if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// Formats of the dates found in pages, besides RFC 3339 and RFC 2822. Numeric dates are read
+/// day first, `Scrape::date_format` tells otherwise.
+const DATE_TIME_FORMATS: [&str; 3] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"];
+const DATE_FORMATS: [&str; 6] = [
+    "%Y-%m-%d",
+    "%Y/%m/%d",
+    "%d/%m/%Y",
+    "%d %B %Y",
+    "%B %d, %Y",
+    "%b %d, %Y",
+];
+
+/// Builds the feed of the page at `url` from the elements picked by `scrape`.
+pub fn feed(url: &str, html: &str, scrape: &Scrape) -> Result<Feed, Error> {
+    let base = Url::parse(url).ok();
+    let item = selector(&scrape.item)?;
+    let title = scrape.title.as_deref().map(selector).transpose()?;
+    let link = selector(scrape.link.as_deref().unwrap_or("a[href]"))?;
+    let date = scrape.date.as_deref().map(selector).transpose()?;
+    let content = scrape.content.as_deref().map(selector).transpose()?;
+
+    let page = Html::parse_document(html);
+    let page_title = page
+        .select(&selector("title")?)
+        .next()
+        .map(text)
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(|| url.to_owned());
+
+    let entries = page
+        .select(&item)
+        .map(|item| {
+            let link_element = item.select(&link).next();
+            let href = link_element
+                .and_then(|el| el.value().attr("href"))
+                .map(
+                    |href| match base.as_ref().and_then(|base| base.join(href).ok()) {
+                        Some(href) => href.to_string(),
+                        None => href.to_owned(),
+                    },
+                );
+            let title = match title {
+                Some(ref title) => item.select(title).next().map(text),
+                None => link_element.map(text),
+            };
+            let published = date
+                .as_ref()
+                .and_then(|date| item.select(date).next())
+                .and_then(|el| {
+                    el.value()
+                        .attr("datetime")
+                        .and_then(|date| parse_date(date, scrape.date_format.as_deref()))
+                        .or_else(|| parse_date(&text(el), scrape.date_format.as_deref()))
+                });
+            let body = match content {
+                Some(ref content) => item.select(content).next().map(|el| el.inner_html()),
+                None => Some(item.inner_html()),
+            };
+            Entry {
+                id: entry_id(href.as_deref(), title.as_deref(), body.as_deref()),
+                title: title.map(|title| Text {
+                    content_type: mime::TEXT_PLAIN,
+                    content: title,
+                    src: None,
+                }),
+                links: href.into_iter().map(html_link).collect(),
+                published,
+                content: body.map(|body| Content {
+                    body: Some(body),
+                    content_type: mime::TEXT_HTML,
+                    length: None,
+                    src: None,
+                }),
+                base: Some(url.to_owned()),
+                ..Entry::default()
+            }
+        })
+        .collect();
+
+    Ok(page_feed(url, page_title, entries))
+}
+
+/// The feed of a page, made of `entries`.
+pub(crate) fn page_feed(url: &str, title: String, entries: Vec<Entry>) -> Feed {
+    Feed {
+        feed_type: FeedType::Atom,
+        id: url.to_owned(),
+        title: Some(Text {
+            content_type: mime::TEXT_PLAIN,
+            content: title,
+            src: None,
+        }),
+        updated: None,
+        authors: Vec::new(),
+        description: None,
+        links: vec![html_link(url.to_owned())],
+        categories: Vec::new(),
+        contributors: Vec::new(),
+        generator: None,
+        icon: None,
+        language: None,
+        logo: None,
+        published: None,
+        rating: None,
+        rights: None,
+        ttl: None,
+        entries,
+    }
+}
+
+fn selector(selector: &str) -> Result<Selector, Error> {
+    Selector::parse(selector).map_err(|err| anyhow!("invalid selector {}: {}", selector, err))
+}
+
+/// Text of an element, with its white spaces collapsed.
+fn text(el: ElementRef) -> String {
+    el.text()
+        .flat_map(|text| text.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn html_link(href: String) -> Link {
+    Link {
+        href,
+        rel: None,
+        media_type: Some("text/html".to_owned()),
+        href_lang: None,
+        title: None,
+        length: None,
+    }
+}
+
+/// Identifies an entry by its link, or by its title and content when it has none, so that it
+/// keeps its id between two scrapes.
+fn entry_id(href: Option<&str>, title: Option<&str>, content: Option<&str>) -> String {
+    if let Some(href) = href {
+        return href.to_owned();
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(title.unwrap_or_default().as_bytes());
+    hasher.update(content.unwrap_or_default().as_bytes());
+    format!("{}", hasher.finalize())
+}
+
+/// Parses `date` in `format` if set, else in one of the usual formats.
+fn parse_date(date: &str, format: Option<&str>) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    if let Some(format) = format {
+        let parsed = NaiveDateTime::parse_from_str(date, format)
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(date, format)
+                    .ok()
+                    .and_then(|date| date.and_hms_opt(0, 0, 0))
+            });
+        if let Some(parsed) = parsed {
+            return Some(parsed.and_utc());
+        }
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(date) {
+        return Some(date.with_timezone(&Utc));
+    }
+    DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(date, format).ok())
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|date| date.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const PAGE: &str = r#"<html><head><title>News</title></head><body>
+<article><h2><a href="/news/first">First news</a></h2><time datetime="2024-03-04T10:00:00Z">March 4</time><p>first</p></article>
+<article><h2>Second news</h2><span class="date">03/04/2024</span><p>second</p></article>
+</body></html>"#;
+
+    fn scrape() -> Scrape {
+        Scrape {
+            item: "article".to_owned(),
+            title: None,
+            link: None,
+            date: Some("time, .date".to_owned()),
+            date_format: None,
+            content: Some("p".to_owned()),
+        }
+    }
+
+    #[test]
+    fn entries_are_scraped() {
+        let scraped = feed("http://example.com/index.html", PAGE, &scrape()).unwrap();
+        assert_eq!(scraped.title.unwrap().content, "News");
+        assert_eq!(scraped.entries.len(), 2);
+
+        let first = &scraped.entries[0];
+        assert_eq!(first.id, "http://example.com/news/first");
+        assert_eq!(first.links[0].href, "http://example.com/news/first");
+        assert_eq!(first.title.as_ref().unwrap().content, "First news");
+        assert_eq!(
+            first.published,
+            Some(Utc.with_ymd_and_hms(2024, 3, 4, 10, 0, 0).unwrap())
+        );
+        assert_eq!(
+            first.content.as_ref().unwrap().body.as_deref(),
+            Some("first")
+        );
+
+        // without link, the entry has no title either, but a stable id
+        let second = &scraped.entries[1];
+        assert!(second.links.is_empty());
+        assert!(second.title.is_none());
+        assert_eq!(
+            second.published,
+            Some(Utc.with_ymd_and_hms(2024, 4, 3, 0, 0, 0).unwrap())
+        );
+        let again = feed("http://example.com/index.html", PAGE, &scrape()).unwrap();
+        assert_eq!(second.id, again.entries[1].id);
+        assert_ne!(second.id, first.id);
+    }
+
+    #[test]
+    fn dates_are_parsed() {
+        let march_4 = Some(Utc.with_ymd_and_hms(2024, 3, 4, 0, 0, 0).unwrap());
+        assert_eq!(parse_date("2024-03-04", None), march_4);
+        assert_eq!(parse_date(" 4 March 2024 ", None), march_4);
+        assert_eq!(parse_date("March 4, 2024", None), march_4);
+        assert_eq!(parse_date("Mon, 04 Mar 2024 00:00:00 +0000", None), march_4);
+        assert_eq!(parse_date("04/03/2024", None), march_4);
+        assert_eq!(parse_date("03/04/2024", Some("%m/%d/%Y")), march_4);
+        assert_eq!(parse_date("yesterday", None), None);
+    }
+}
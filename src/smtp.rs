@@ -0,0 +1,75 @@
+use crate::{imap::Security, sync};
+use anyhow::{Context, Error};
+use lettre::{
+    address::Envelope, transport::smtp::authentication::Credentials, Address, AsyncSmtpTransport,
+    AsyncTransport, Tokio1Executor,
+};
+use std::sync::Arc;
+
+pub struct InnerOutput {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    envelope: Envelope,
+    email: String,
+}
+
+pub type Output = Arc<InnerOutput>;
+
+/// Creates an output submitting messages to `email` through the SMTP server `host`, from `email`
+/// too as servers refuse senders they do not own. Login is skipped when `username` is empty.
+pub fn new_output(
+    host: &str,
+    port: u16,
+    security: Security,
+    username: &str,
+    password: &str,
+    email: &str,
+) -> Result<Output, Error> {
+    let builder = match security {
+        Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+        Security::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        Security::Plain => {
+            log::warn!("connecting to {}:{} without encryption", host, port);
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        }
+    };
+    let mut builder = builder.port(port);
+    if !username.is_empty() {
+        builder = builder.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+    }
+    let address: Address = email
+        .parse()
+        .with_context(|| format!("invalid email {}", email))?;
+    Ok(Arc::new(InnerOutput {
+        transport: builder.build(),
+        envelope: Envelope::new(Some(address.clone()), vec![address])?,
+        email: email.to_owned(),
+    }))
+}
+
+impl sync::Output for Output {
+    /// A SMTP server cannot be queried, delivered entries are only known from the state.
+    async fn contains(&self, _feed: &str, _id: &str, _folder: Option<&str>) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    async fn append(
+        &self,
+        _feed: &str,
+        id: &str,
+        mail: &Vec<u8>,
+        folder: Option<&str>,
+    ) -> Result<(), Error> {
+        if let Some(folder) = folder {
+            log::debug!("{}: folder {} ignored by SMTP output", id, folder);
+        }
+        self.transport
+            .send_raw(&self.envelope, mail)
+            .await
+            .with_context(|| format!("could not send {}", id))?;
+        Ok(())
+    }
+
+    fn sender(&self) -> Option<&str> {
+        Some(&self.email)
+    }
+}
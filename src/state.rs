@@ -0,0 +1,286 @@
+use crate::{fetch::Validators, sync, watch};
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+
+/// What is remembered between two syncs.
+#[derive(Deserialize, Serialize, Default)]
+pub struct State {
+    #[serde(default)]
+    pub feeds: BTreeMap<String, FeedState>,
+    #[serde(default)]
+    pub folders: BTreeMap<String, FolderState>,
+    #[serde(default)]
+    pub hosts: BTreeMap<String, HostState>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct FeedState {
+    /// message ids of the entries already delivered
+    #[serde(default)]
+    pub delivered: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_fetch: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// delivery attempts of the entries that could not be delivered, by message id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failed: BTreeMap<String, u32>,
+    /// validators of the last response
+    #[serde(default, flatten)]
+    pub validators: Validators,
+    /// text of a watched page when its last change was delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<watch::Snapshot>,
+}
+
+/// Message ids found in a mail folder, up to the message `last_uid`.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct FolderState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid_validity: Option<u32>,
+    #[serde(default)]
+    pub last_uid: u32,
+    #[serde(default)]
+    pub ids: BTreeSet<String>,
+}
+
+/// What a server asked of its clients.
+#[derive(Deserialize, Serialize, Default)]
+pub struct HostState {
+    /// the server is not fetched again before this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<DateTime<Utc>>,
+}
+
+pub struct InnerStore {
+    path: PathBuf,
+    state: Mutex<State>,
+    /// held while saving, so that saves do not overwrite each other
+    saving: Mutex<()>,
+    /// the state is never saved, e.g. during a dry run
+    read_only: bool,
+}
+
+pub type Store = Arc<InnerStore>;
+
+/// Default location of the state file, `~/.local/state/feed2imap/state.toml` on Linux.
+pub fn default_path() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "feed2imap")?;
+    let dir = dirs.state_dir().unwrap_or_else(|| dirs.data_local_dir());
+    Some(dir.join("state.toml"))
+}
+
+/// Loads the state stored at `path`, starting from an empty state if it does not exist yet.
+pub async fn load<P: AsRef<Path>>(path: P) -> Result<Store, Error> {
+    open(path.as_ref(), false).await
+}
+
+/// Loads the state stored at `path`, never saving it back.
+pub async fn load_read_only<P: AsRef<Path>>(path: P) -> Result<Store, Error> {
+    open(path.as_ref(), true).await
+}
+
+async fn open(path: &Path, read_only: bool) -> Result<Store, Error> {
+    let state = match tokio::fs::read_to_string(path).await {
+        Ok(content) => toml::from_str(&content)
+            .with_context(|| format!("could not parse state {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => State::default(),
+        Err(err) => {
+            return Err(err).with_context(|| format!("could not read state {}", path.display()))
+        }
+    };
+    Ok(Arc::new(InnerStore {
+        path: path.to_owned(),
+        state: Mutex::new(state),
+        saving: Mutex::new(()),
+        read_only,
+    }))
+}
+
+impl InnerStore {
+    pub async fn is_delivered(&self, feed: &str, id: &str) -> bool {
+        let state = self.state.lock().await;
+        state
+            .feeds
+            .get(feed)
+            .is_some_and(|feed_state| feed_state.delivered.contains(id))
+    }
+
+    pub async fn mark_delivered(&self, feed: &str, id: &str) {
+        let mut state = self.state.lock().await;
+        let feed_state = state.feeds.entry(feed.to_owned()).or_default();
+        feed_state.delivered.insert(id.to_owned());
+    }
+
+    /// Records when `feed` was last fetched and the error it ended with, if any.
+    pub async fn record_fetch(&self, feed: &str, result: &Result<(), Error>) {
+        let mut state = self.state.lock().await;
+        let feed_state = state.feeds.entry(feed.to_owned()).or_default();
+        feed_state.last_fetch = Some(Utc::now());
+        feed_state.last_error = result.as_ref().err().map(|err| format!("{:#}", err));
+    }
+
+    pub async fn validators(&self, feed: &str) -> Validators {
+        let state = self.state.lock().await;
+        state
+            .feeds
+            .get(feed)
+            .map(|feed_state| feed_state.validators.clone())
+            .unwrap_or_default()
+    }
+
+    pub async fn set_validators(&self, feed: &str, validators: Validators) {
+        let mut state = self.state.lock().await;
+        let feed_state = state.feeds.entry(feed.to_owned()).or_default();
+        feed_state.validators = validators;
+    }
+
+    pub async fn failed(&self, feed: &str) -> BTreeMap<String, u32> {
+        let state = self.state.lock().await;
+        state
+            .feeds
+            .get(feed)
+            .map(|feed_state| feed_state.failed.clone())
+            .unwrap_or_default()
+    }
+
+    pub async fn set_failed(&self, feed: &str, failed: BTreeMap<String, u32>) {
+        let mut state = self.state.lock().await;
+        let feed_state = state.feeds.entry(feed.to_owned()).or_default();
+        feed_state.failed = failed;
+    }
+
+    pub async fn snapshot(&self, feed: &str) -> Option<watch::Snapshot> {
+        let state = self.state.lock().await;
+        state.feeds.get(feed)?.snapshot.clone()
+    }
+
+    pub async fn set_snapshot(&self, feed: &str, snapshot: watch::Snapshot) {
+        let mut state = self.state.lock().await;
+        let feed_state = state.feeds.entry(feed.to_owned()).or_default();
+        feed_state.snapshot = Some(snapshot);
+    }
+
+    /// Moves the state of feed `from` to feed `to`, e.g. when its url changed.
+    pub async fn rename_feed(&self, from: &str, to: &str) {
+        let mut state = self.state.lock().await;
+        if let Some(feed_state) = state.feeds.remove(from) {
+            state.feeds.insert(to.to_owned(), feed_state);
+        }
+    }
+
+    /// When `host` may be fetched again, if it asked not to be fetched yet.
+    pub async fn retry_after(&self, host: &str) -> Option<DateTime<Utc>> {
+        let mut state = self.state.lock().await;
+        let retry_after = state.hosts.get(host)?.retry_after;
+        match retry_after {
+            Some(until) if until > Utc::now() => Some(until),
+            _ => {
+                state.hosts.remove(host);
+                None
+            }
+        }
+    }
+
+    pub async fn set_retry_after(&self, host: &str, until: DateTime<Utc>) {
+        let mut state = self.state.lock().await;
+        let host_state = state.hosts.entry(host.to_owned()).or_default();
+        host_state.retry_after = Some(until);
+    }
+
+    pub async fn folder(&self, folder: &str) -> FolderState {
+        let state = self.state.lock().await;
+        state.folders.get(folder).cloned().unwrap_or_default()
+    }
+
+    pub async fn set_folder(&self, folder: &str, folder_state: FolderState) {
+        let mut state = self.state.lock().await;
+        state.folders.insert(folder.to_owned(), folder_state);
+    }
+
+    /// Writes the state back to its file, through a temporary file so that an interrupted save
+    /// does not lose the previous state.
+    pub async fn save(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        let _saving = self.saving.lock().await;
+        let content = {
+            let state = self.state.lock().await;
+            toml::to_string(&*state)?
+        };
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("could not create {}", dir.display()))?;
+        }
+        let tmp_path = self.path.with_extension("toml.tmp");
+        tokio::fs::write(&tmp_path, content)
+            .await
+            .with_context(|| format!("could not write {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, &self.path)
+            .await
+            .with_context(|| format!("could not write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Output remembering delivered entries in the state, so that they are not sent again once
+/// deleted from `inner`.
+#[derive(Clone)]
+pub struct Output<TOutput> {
+    inner: TOutput,
+    store: Store,
+    /// also look for entries in `inner`, e.g. those delivered before the state existed
+    reconcile: bool,
+}
+
+impl<TOutput> Output<TOutput> {
+    pub fn new(inner: TOutput, store: Store, reconcile: bool) -> Output<TOutput> {
+        Output {
+            inner,
+            store,
+            reconcile,
+        }
+    }
+}
+
+impl<TOutput> sync::Output for Output<TOutput>
+where
+    TOutput: sync::Output + Sync,
+{
+    async fn contains(&self, feed: &str, id: &str, folder: Option<&str>) -> Result<bool, Error> {
+        if self.store.is_delivered(feed, id).await {
+            return Ok(true);
+        }
+        if self.reconcile && self.inner.contains(feed, id, folder).await? {
+            self.store.mark_delivered(feed, id).await;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn append(
+        &self,
+        feed: &str,
+        id: &str,
+        mail: &Vec<u8>,
+        folder: Option<&str>,
+    ) -> Result<(), Error> {
+        self.inner.append(feed, id, mail, folder).await?;
+        self.store.mark_delivered(feed, id).await;
+        Ok(())
+    }
+
+    fn sender(&self) -> Option<&str> {
+        self.inner.sender()
+    }
+}
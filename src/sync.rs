@@ -1,22 +1,51 @@
-use std::{marker::Send, sync::Arc};
+use std::{collections::BTreeMap, marker::Send, sync::Arc};
 
-use anyhow::Error;
-use futures::future::try_join_all;
+use anyhow::{anyhow, Error};
+use feed_rs::model::{Entry, Feed};
 
-use crate::{fetch, transform};
+use crate::{
+    fetch,
+    scheduler::{self, Scheduler},
+    state, transform,
+};
 
 pub struct Syncer {
     name: String,
     email: String,
+    state: state::Store,
+    scheduler: Scheduler,
+    fetcher: fetch::Fetcher,
+    /// syncs trying to make a mail of an entry before giving up on it, never given up if `None`
+    max_attempts: Option<u32>,
+}
+
+/// Why an entry could not be delivered.
+enum EntryError {
+    /// the output could not be reached, the entry is not to blame
+    Output(Error),
+    /// the entry could not be made into a mail
+    Entry(Error),
 }
 
 pub trait Output {
-    fn contains(&self, id: &str) -> bool;
+    fn contains(
+        &self,
+        feed: &str,
+        id: &str,
+        folder: Option<&str>,
+    ) -> impl std::future::Future<Output = Result<bool, Error>> + std::marker::Send;
     fn append(
         &self,
+        feed: &str,
+        id: &str,
         mail: &Vec<u8>,
         folder: Option<&str>,
     ) -> impl std::future::Future<Output = Result<(), Error>> + std::marker::Send;
+    /// address mails must be sent from, e.g. to a server refusing other senders, the address of
+    /// the feed if `None`
+    fn sender(&self) -> Option<&str> {
+        None
+    }
 }
 
 pub trait Reporter {
@@ -28,6 +57,14 @@ pub trait Reporter {
         count: u64,
     ) -> impl std::future::Future<Output = ()> + std::marker::Send;
     fn on_entry(&self, feed: &str) -> impl std::future::Future<Output = ()> + Send;
+    /// the feed did not change since the last sync, and was skipped
+    fn on_unchanged(&self, feed: &str) -> impl std::future::Future<Output = ()> + Send;
+    /// the feed is permanently redirected to `url`
+    fn on_moved(&self, feed: &str, url: &str) -> impl std::future::Future<Output = ()> + Send;
+    /// the feed answered `410 Gone`, it should be disabled
+    fn on_gone(&self, feed: &str) -> impl std::future::Future<Output = ()> + Send;
+    /// the feed is invalid, and could only be parsed once repaired
+    fn on_repaired(&self, feed: &str) -> impl std::future::Future<Output = ()> + Send;
     fn on_end(
         &self,
         feed: &str,
@@ -37,13 +74,27 @@ pub trait Reporter {
 
 pub trait Input {
     fn url(&self) -> &str;
+    /// folder where entries of this feed are stored, the output default folder if `None`
+    fn folder(&self) -> Option<&str>;
+    fn options(&self) -> &fetch::Options;
 }
 
 impl Syncer {
-    pub fn new(name: &String, email: &String) -> Arc<Syncer> {
+    pub fn new(
+        name: &str,
+        email: &str,
+        state: state::Store,
+        scheduler: Scheduler,
+        fetcher: fetch::Fetcher,
+        max_attempts: Option<u32>,
+    ) -> Arc<Syncer> {
         Arc::new(Syncer {
-            name: name.clone(),
-            email: email.clone(),
+            name: name.to_owned(),
+            email: email.to_owned(),
+            state,
+            scheduler,
+            fetcher,
+            max_attempts,
         })
     }
 
@@ -52,11 +103,11 @@ impl Syncer {
         inputs: &Vec<TInput>,
         output: TOutput,
         reporter: TReporter,
-    ) -> Result<(), Error>
+    ) -> Result<SyncReport, Error>
     where
         TOutput: Output + Sync + Send + Clone + 'static,
         TReporter: Reporter + Send + Clone + std::marker::Sync + 'static,
-        TInput: Input + Send + Clone + 'static,
+        TInput: Input + Send + Sync + Clone + 'static,
     {
         let mut tasks = Vec::with_capacity(inputs.len());
         for input in inputs {
@@ -67,13 +118,19 @@ impl Syncer {
             let task = tokio::spawn(async {
                 task_self
                     .sync_feed(task_input, task_output, task_reporter)
-                    .await?;
-                Ok::<(), Error>(())
+                    .await
             });
-            tasks.push(task);
+            tasks.push((input.url().to_owned(), task));
         }
-        let _results = try_join_all(tasks).await?;
-        Ok(())
+        let mut report = SyncReport::default();
+        for (url, task) in tasks {
+            let feed_report = task.await.unwrap_or_else(|err| FeedReport {
+                error: Some(Error::new(err).context("sync task failed")),
+                ..FeedReport::new(&url)
+            });
+            report.feeds.push(feed_report);
+        }
+        Ok(report)
     }
 
     async fn sync_feed<TOutput, TReporter, TInput>(
@@ -81,50 +138,369 @@ impl Syncer {
         input: TInput,
         output: TOutput,
         reporter: TReporter,
-    ) -> Result<(), Error>
+    ) -> FeedReport
     where
         TOutput: Output,
         TReporter: Reporter + std::marker::Sync,
-        TInput: Input,
+        TInput: Input + Sync,
     {
         let url = input.url();
-        let result = self.sync_feed_entries(output, url, &reporter).await;
+        let mut feed_report = FeedReport::new(url);
+        let result = self
+            .sync_feed_entries(output, &input, &reporter, &mut feed_report)
+            .await
+            .and_then(|()| match (feed_report.failed, feed_report.given_up) {
+                (0, 0) => Ok(()),
+                (failed, 0) => Err(anyhow!("{} entries could not be delivered", failed)),
+                (failed, given_up) => Err(anyhow!(
+                    "{} entries could not be delivered, {} given up",
+                    failed + given_up,
+                    given_up
+                )),
+            });
+        self.state.record_fetch(url, &result).await;
+        // saved after each feed, so that an interrupted sync does not deliver its entries again
+        if let Err(err) = self.state.save().await {
+            log::warn!("{}: could not save state: {:#}", url, err);
+        }
         reporter.on_end(url, &result).await;
-        return result;
+        feed_report.error = result.err();
+        feed_report
     }
 
-    async fn sync_feed_entries<TOutput, TReporter>(
-        self: Arc<Self>,
+    async fn sync_feed_entries<TOutput, TReporter, TInput>(
+        self: &Arc<Self>,
         output: TOutput,
-        url: &str,
+        input: &TInput,
         reporter: &TReporter,
+        feed_report: &mut FeedReport,
     ) -> Result<(), Error>
     where
         TOutput: Output,
         TReporter: Reporter + std::marker::Sync,
+        TInput: Input + Sync,
     {
+        let (url, folder) = (input.url(), input.folder());
+        // the url of a feed made by a command is never fetched
+        let host = match input.options().command {
+            Some(_) => None,
+            None => scheduler::host_of(url),
+        };
+        let permit = self.scheduler.acquire(host.as_deref()).await?;
         log::info!("syncing {}", url);
-        reporter.on_begin(&url).await;
-        let full_feed = fetch::url(&url).await?;
+        reporter.on_begin(url).await;
+        // a rate limited host is left alone until the time it asked for
+        if let Some(ref host) = host {
+            if let Some(until) = self.state.retry_after(host).await {
+                return Err(anyhow!(
+                    "{} asked to retry after {}",
+                    host,
+                    until.format("%Y-%m-%d %H:%M:%S UTC")
+                ));
+            }
+        }
+        // the entries to retry are only sent back by a full response
+        let failed = self.state.failed(url).await;
+        let validators = if failed.values().any(|attempts| !self.is_given_up(*attempts)) {
+            fetch::Validators::default()
+        } else {
+            self.state.validators(url).await
+        };
+        let snapshot = self.state.snapshot(url).await;
+        let fetched = match self
+            .fetcher
+            .url_if_modified(url, input.options(), &validators, snapshot.as_ref())
+            .await
+        {
+            // reported until the feed is disabled, not failing every sync until then
+            Err(err) if err.is::<fetch::Gone>() => {
+                log::warn!("{}: {:#}", url, err);
+                feed_report.gone = true;
+                reporter.on_gone(url).await;
+                return Ok(());
+            }
+            Err(err) => {
+                if let (Some(retry_later), Some(host)) =
+                    (err.downcast_ref::<fetch::RetryLater>(), host.as_deref())
+                {
+                    self.state.set_retry_after(host, retry_later.until).await;
+                }
+                return Err(err);
+            }
+            Ok(fetched) => fetched,
+        };
+        drop(permit);
+        if fetched.repaired {
+            feed_report.repaired = true;
+            reporter.on_repaired(url).await;
+        }
+        if let Some(ref moved_to) = fetched.moved_to {
+            feed_report.moved_to = Some(moved_to.clone());
+            reporter.on_moved(url, moved_to).await;
+        }
+        let Some(full_feed) = fetched.feed else {
+            log::info!("{} not modified", url);
+            feed_report.unchanged = true;
+            reporter.on_unchanged(url).await;
+            return Ok(());
+        };
         let title: String = transform::extract_feed_title(&full_feed)?
             .chars()
             .take(20)
             .collect();
         reporter
-            .on_entries_count(&url, &title, full_feed.entries.len() as u64)
+            .on_entries_count(url, &title, full_feed.entries.len() as u64)
             .await;
-        let result = for entry in &full_feed.entries {
-            let id = transform::extract_message_id(&full_feed, &entry);
-            if !output.contains(&id) {
-                let mail = transform::extract_message(&self.name, &self.email, &full_feed, entry)?;
-                log::debug!("{}: {} appending to mail", url, id);
-                output.append(&mail, None).await?;
-                log::debug!("{}: {} appended to mail", url, id);
-            } else {
-                log::debug!("{}: {} already in mail", url, id);
+        // entries no longer in the feed are forgotten
+        let mut still_failed = BTreeMap::new();
+        for entry in &full_feed.entries {
+            let id = transform::extract_message_id(&full_feed, entry);
+            let attempts = failed.get(&id).copied().unwrap_or_default();
+            if self.is_given_up(attempts) {
+                log::warn!("{}: {} given up after {} attempts", url, id, attempts);
+                still_failed.insert(id, attempts);
+                feed_report.given_up += 1;
+                reporter.on_entry(url).await;
+                continue;
             }
-            reporter.on_entry(&url).await;
-        };
-        Ok(result)
+            match self
+                .sync_entry(&output, url, folder, &full_feed, entry, &id)
+                .await
+            {
+                Ok(true) => feed_report.new += 1,
+                Ok(false) => feed_report.skipped += 1,
+                Err(EntryError::Output(err)) => {
+                    log::warn!("{}: {} failed: {:#}", url, id, err);
+                    still_failed.insert(id, attempts);
+                    feed_report.failed += 1;
+                }
+                Err(EntryError::Entry(err)) => {
+                    log::warn!("{}: {} failed: {:#}", url, id, err);
+                    still_failed.insert(id, attempts + 1);
+                    feed_report.failed += 1;
+                }
+            }
+            reporter.on_entry(url).await;
+        }
+        self.state.set_failed(url, still_failed).await;
+        self.state.set_validators(url, fetched.validators).await;
+        // the next change of a watched page is told from the last delivered one
+        if let (0, Some(snapshot)) = (feed_report.failed, fetched.snapshot) {
+            self.state.set_snapshot(url, snapshot).await;
+        }
+        Ok(())
+    }
+
+    /// Delivers `entry` unless already delivered, and tells whether it was.
+    async fn sync_entry<TOutput>(
+        &self,
+        output: &TOutput,
+        url: &str,
+        folder: Option<&str>,
+        full_feed: &Feed,
+        entry: &Entry,
+        id: &str,
+    ) -> Result<bool, EntryError>
+    where
+        TOutput: Output,
+    {
+        if output
+            .contains(url, id, folder)
+            .await
+            .map_err(EntryError::Output)?
+        {
+            log::debug!("{}: {} already in mail", url, id);
+            return Ok(false);
+        }
+        let mail =
+            transform::extract_message(&self.name, &self.email, output.sender(), full_feed, entry)
+                .map_err(EntryError::Entry)?;
+        log::debug!("{}: {} appending to mail", url, id);
+        output
+            .append(url, id, &mail, folder)
+            .await
+            .map_err(EntryError::Output)?;
+        log::debug!("{}: {} appended to mail", url, id);
+        Ok(true)
+    }
+
+    /// Whether an entry failing `attempts` times is no longer tried.
+    fn is_given_up(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+}
+
+/// Outcome of the sync of a feed.
+pub struct FeedReport {
+    pub url: String,
+    /// why the feed, or some of its entries, could not be synced
+    pub error: Option<Error>,
+    /// entries delivered during this sync
+    pub new: u64,
+    /// entries already delivered
+    pub skipped: u64,
+    /// entries that could not be delivered
+    pub failed: u64,
+    /// entries that failed too many times, and are no longer tried
+    pub given_up: u64,
+    /// the feed did not change since last sync
+    pub unchanged: bool,
+    /// new url of the feed, when permanently redirected
+    pub moved_to: Option<String>,
+    /// the feed will never be available again
+    pub gone: bool,
+    /// the feed is invalid, and was repaired
+    pub repaired: bool,
+}
+
+impl FeedReport {
+    fn new(url: &str) -> FeedReport {
+        FeedReport {
+            url: url.to_owned(),
+            error: None,
+            new: 0,
+            skipped: 0,
+            failed: 0,
+            given_up: 0,
+            unchanged: false,
+            moved_to: None,
+            gone: false,
+            repaired: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SyncReport {
+    pub feeds: Vec<FeedReport>,
+}
+
+impl SyncReport {
+    pub fn failures(&self) -> impl Iterator<Item = &FeedReport> {
+        self.feeds.iter().filter(|feed| feed.error.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbox;
+    use std::time::Duration;
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Fixture</title>
+    <link>http://example.com/</link>
+    <item>
+      <title>First</title>
+      <link>http://example.com/first</link>
+      <guid>first</guid>
+      <description>From the first entry</description>
+    </item>
+    <item>
+      <title>Second</title>
+      <link>http://example.com/second</link>
+      <guid>second</guid>
+      <description>the second entry</description>
+    </item>
+  </channel>
+</rss>
+"#;
+
+    #[derive(Clone)]
+    struct TestInput {
+        url: String,
+        options: fetch::Options,
+    }
+
+    impl Input for TestInput {
+        fn url(&self) -> &str {
+            &self.url
+        }
+
+        fn folder(&self) -> Option<&str> {
+            None
+        }
+
+        fn options(&self) -> &fetch::Options {
+            &self.options
+        }
+    }
+
+    #[derive(Clone)]
+    struct NoReporter;
+
+    impl Reporter for NoReporter {
+        async fn on_begin(&self, _feed: &str) {}
+        async fn on_entries_count(&self, _feed: &str, _title: &str, _count: u64) {}
+        async fn on_entry(&self, _feed: &str) {}
+        async fn on_unchanged(&self, _feed: &str) {}
+        async fn on_moved(&self, _feed: &str, _url: &str) {}
+        async fn on_gone(&self, _feed: &str) {}
+        async fn on_repaired(&self, _feed: &str) {}
+        async fn on_end(&self, _feed: &str, _result: &Result<(), Error>) {}
+    }
+
+    fn fetcher() -> fetch::Fetcher {
+        fetch::Fetcher::new(&fetch::Settings {
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            max_size: 1024 * 1024,
+            proxy: None,
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            max_retry_delay: Duration::ZERO,
+            lenient: false,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn file_feed_is_synced_to_mbox() {
+        let dir = std::env::temp_dir().join(format!("feed2imap-sync-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let feed_path = dir.join("feed.xml");
+        tokio::fs::write(&feed_path, FEED).await.unwrap();
+        let mbox_path = dir.join("feeds.mbox");
+        let store = state::load(dir.join("state.toml")).await.unwrap();
+        let syncer = Syncer::new(
+            "Feeds",
+            "feeds@example.com",
+            store.clone(),
+            Scheduler::new(1, 1, Duration::ZERO),
+            fetcher(),
+            None,
+        );
+        let inputs = vec![TestInput {
+            url: format!("file://{}", feed_path.display()),
+            options: fetch::Options::default(),
+        }];
+
+        let report = syncer
+            .clone()
+            .sync(&inputs, mbox::new_output(&mbox_path, false), NoReporter)
+            .await
+            .unwrap();
+        let first = &report.feeds[0];
+        assert!(first.error.is_none());
+        assert_eq!((first.new, first.skipped, first.failed), (2, 0, 0));
+
+        // the file did not change since
+        let report = syncer
+            .sync(&inputs, mbox::new_output(&mbox_path, false), NoReporter)
+            .await
+            .unwrap();
+        assert!(report.feeds[0].unchanged);
+
+        let mbox = tokio::fs::read_to_string(&mbox_path).await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(mbox.matches("\nSubject: ").count(), 2);
+        assert_eq!(
+            mbox.lines()
+                .filter(|line| line.starts_with("From "))
+                .count(),
+            2
+        );
     }
 }
@@ -11,18 +11,25 @@ use std::fmt::Display;
 
 mod html;
 
+/// Builds the mail of `entry`, sent to `email`. It is sent from the feed address, or from
+/// `sender` when set, the feed address being then the one to reply to.
 pub fn extract_message(
     name: &str,
     email: &str,
+    sender: Option<&str>,
     full_feed: &feed_rs::model::Feed,
     entry: &feed_rs::model::Entry,
 ) -> Result<Vec<u8>, Error> {
-    Ok(MessageBuilder::new()
+    let title = extract_feed_title(full_feed)?;
+    let feed_address = Address::new_address(title.clone().into(), extract_email(full_feed, entry)?);
+    let message = match sender {
+        Some(sender) => MessageBuilder::new()
+            .from(Address::new_address(title.into(), sender))
+            .reply_to(feed_address),
+        None => MessageBuilder::new().from(feed_address),
+    };
+    Ok(message
         .message_id(extract_message_id(full_feed, entry))
-        .from(Address::new_address(
-            extract_feed_title(full_feed)?.into(),
-            extract_email(full_feed, entry)?,
-        ))
         .to(Address::new_address(name.into(), email))
         .date(extract_published_date(entry))
         .subject(extract_title(entry))
@@ -178,3 +185,44 @@ fn unknown_text() -> Text {
         src: None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed() -> feed_rs::model::Feed {
+        let xml = br#"<rss version="2.0"><channel><title>Example</title><link>http://example.com/</link>
+<item><title>First</title><link>http://example.com/first</link><guid>first</guid><description>first entry</description></item>
+</channel></rss>"#;
+        feed_rs::parser::parse(&xml[..]).unwrap()
+    }
+
+    fn headers(mail: &[u8]) -> String {
+        let mail = String::from_utf8_lossy(mail);
+        mail[..mail.find("\r\n\r\n").unwrap()].to_owned()
+    }
+
+    #[test]
+    fn mail_is_from_the_feed() {
+        let feed = feed();
+        let mail = extract_message("John", "john@example.org", None, &feed, &feed.entries[0]);
+        let headers = headers(&mail.unwrap());
+        assert!(headers.contains("From: \"Example\" <rss@example.com>"));
+        assert!(!headers.contains("Reply-To:"));
+    }
+
+    #[test]
+    fn mail_is_from_the_sender_replying_to_the_feed() {
+        let feed = feed();
+        let mail = extract_message(
+            "John",
+            "john@example.org",
+            Some("john@example.org"),
+            &feed,
+            &feed.entries[0],
+        );
+        let headers = headers(&mail.unwrap());
+        assert!(headers.contains("From: \"Example\" <john@example.org>"));
+        assert!(headers.contains("Reply-To: \"Example\" <rss@example.com>"));
+    }
+}
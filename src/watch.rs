@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use feed_rs::model::{Content, Entry, Feed, Text};
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+use crate::scrape::{html_link, page_feed};
+
+/// A page watched for changes, instead of a feed.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct Watch {
+    /// CSS selector of the watched part of the page, the whole page if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+}
+
+/// What a watched page showed when its last change was delivered.
+#[derive(Deserialize, Serialize, Default, Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    /// text of the watched part of the page
+    pub text: String,
+    /// changes delivered so far, telling apart a page changing back to a previous text
+    #[serde(default)]
+    pub changes: u64,
+}
+
+/// Unchanged lines shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+/// Elements starting a new line of the snapshot.
+const BLOCK_ELEMENTS: [&str; 35] = [
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "details",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Elements whose text is not what the page shows.
+const HIDDEN_ELEMENTS: [&str; 4] = ["script", "style", "noscript", "template"];
+
+impl Watch {
+    /// Text of the watched part of the page, one line per block of text.
+    pub fn snapshot(&self, html: &str) -> Result<String, Error> {
+        let page = Html::parse_document(html);
+        let selector = self.selector.as_deref().unwrap_or("body");
+        let selector = Selector::parse(selector)
+            .map_err(|err| anyhow!("invalid selector {}: {}", selector, err))?;
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for el in page.select(&selector) {
+            push_text(el, &mut lines, &mut line);
+            end_line(&mut lines, &mut line);
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+/// Appends the text of `el` to `line`, ending it at the start and end of each block element.
+fn push_text(el: ElementRef, lines: &mut Vec<String>, line: &mut String) {
+    for child in el.children() {
+        if let Some(text) = child.value().as_text() {
+            line.push_str(text);
+        } else if let Some(child) = ElementRef::wrap(child) {
+            let name = child.value().name();
+            if HIDDEN_ELEMENTS.contains(&name) {
+                continue;
+            }
+            let block = BLOCK_ELEMENTS.contains(&name);
+            if block {
+                end_line(lines, line);
+            }
+            push_text(child, lines, line);
+            if block {
+                end_line(lines, line);
+            }
+        }
+    }
+}
+
+/// Adds `line`, with its white spaces collapsed, to `lines` unless it is blank.
+fn end_line(lines: &mut Vec<String>, line: &mut String) {
+    let text = line.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !text.is_empty() {
+        lines.push(text);
+    }
+    line.clear();
+}
+
+/// Builds the feed of a watched page now showing `text`, with one entry showing what changed
+/// since the `previous` snapshot, or no entry if nothing changed. Returns it with the snapshot
+/// the next fetch is compared to.
+pub fn feed(url: &str, title: &str, previous: Option<&Snapshot>, text: &str) -> (Feed, Snapshot) {
+    let previous_text = previous.map(|previous| previous.text.as_str());
+    let changes = previous
+        .map(|previous| previous.changes)
+        .unwrap_or_default();
+    if previous_text == Some(text) {
+        let snapshot = Snapshot {
+            text: text.to_owned(),
+            changes,
+        };
+        return (page_feed(url, title.to_owned(), Vec::new()), snapshot);
+    }
+    // the same change keeps its id until delivered, but a page changing back to a previous
+    // text is a new change
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(previous_text.unwrap_or_default().as_bytes());
+    hasher.update(text.as_bytes());
+    hasher.update(&changes.to_be_bytes());
+    let subject = match previous {
+        Some(_) => format!("{} changed", title),
+        None => title.to_owned(),
+    };
+    let entry = Entry {
+        id: format!("{}", hasher.finalize()),
+        title: Some(Text {
+            content_type: mime::TEXT_PLAIN,
+            content: subject,
+            src: None,
+        }),
+        links: vec![html_link(url.to_owned())],
+        published: Some(Utc::now()),
+        content: Some(Content {
+            body: Some(diff(previous_text.unwrap_or_default(), text)),
+            content_type: mime::TEXT_HTML,
+            length: None,
+            src: None,
+        }),
+        ..Entry::default()
+    };
+    let snapshot = Snapshot {
+        text: text.to_owned(),
+        changes: changes + 1,
+    };
+    (page_feed(url, title.to_owned(), vec![entry]), snapshot)
+}
+
+/// Title of the page, its url if it has none.
+pub fn title(url: &str, html: &str) -> String {
+    let page = Html::parse_document(html);
+    Selector::parse("title")
+        .ok()
+        .and_then(|selector| page.select(&selector).next())
+        .map(|title| title.text().collect::<String>().trim().to_owned())
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(|| url.to_owned())
+}
+
+/// The lines removed and added between two snapshots, with some unchanged lines around them.
+fn diff(previous: &str, snapshot: &str) -> String {
+    let diff = TextDiff::from_lines(previous, snapshot);
+    let mut html = String::new();
+    for (i, group) in diff.grouped_ops(CONTEXT_LINES).iter().enumerate() {
+        if i > 0 {
+            html.push_str("<hr>\n");
+        }
+        for op in group {
+            for change in diff.iter_changes(op) {
+                let line = html_escape::encode_text(change.value().trim_end());
+                let line = match change.tag() {
+                    ChangeTag::Equal => format!("<p>{}</p>\n", line),
+                    ChangeTag::Delete => {
+                        format!("<p style=\"background: #ffebe9\"><del>{}</del></p>\n", line)
+                    }
+                    ChangeTag::Insert => {
+                        format!("<p style=\"background: #e6ffec\"><ins>{}</ins></p>\n", line)
+                    }
+                };
+                html.push_str(&line);
+            }
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn snapshot_has_one_line_per_block() {
+        let html = "<body><p>a <b>b</b> c</p><script>hidden()</script><ul><li>d</li><li>e<br>f</li></ul></body>";
+        let snapshot = Watch::default().snapshot(html).unwrap();
+        assert_eq!(snapshot, "a b c\nd\ne\nf");
+    }
+
+    #[test]
+    fn same_change_keeps_its_id() {
+        let previous = Snapshot {
+            text: "a".to_owned(),
+            changes: 1,
+        };
+        let (changed, _) = feed("http://example.com/", "Example", Some(&previous), "b");
+        let (changed_again, _) = feed("http://example.com/", "Example", Some(&previous), "b");
+        assert_eq!(changed.entries[0].id, changed_again.entries[0].id);
+    }
+
+    #[test]
+    fn change_back_is_a_new_entry() {
+        let url = "http://example.com/";
+        let (first, a) = feed(url, "Example", None, "a");
+        let (to_b, b) = feed(url, "Example", Some(&a), "b");
+        let (back_to_a, a_again) = feed(url, "Example", Some(&b), "a");
+        let (to_b_again, b_again) = feed(url, "Example", Some(&a_again), "b");
+        let ids: BTreeSet<&str> = [&first, &to_b, &back_to_a, &to_b_again]
+            .iter()
+            .map(|feed| feed.entries[0].id.as_str())
+            .collect();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(b_again.changes, 4);
+
+        let (unchanged, snapshot) = feed(url, "Example", Some(&b_again), "b");
+        assert!(unchanged.entries.is_empty());
+        assert_eq!(snapshot, b_again);
+    }
+}